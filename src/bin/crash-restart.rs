@@ -0,0 +1,102 @@
+//! Runs `k-log` or `g-counter` on a local cluster, crashing and restarting
+//! nodes from their storage while checking the workload's invariants.
+//!
+//! usage: crash-restart <k-log|g-counter> [kill|stop]
+
+use anyhow::bail;
+use rustengan::{
+    checker::{NeverDecreasing, OffsetsNeverReused},
+    cluster::{Cluster, Fault},
+    failure_detector::DETECTOR_ENV,
+    rng::Rng,
+    storage::DATA_DIR_ENV,
+};
+use serde_json::{json, Value};
+
+const NODES: usize = 3;
+const REQUESTS: usize = 400;
+/// A node is crashed every this many requests, and restarted halfway to the
+/// next crash.
+const CRASH_EVERY: usize = 50;
+const KEYS: usize = 3;
+
+fn request(workload: &str, n: usize, rng: &mut Rng) -> Value {
+    match workload {
+        "k-log" if rng.below(5) == 0 => {
+            let offsets: serde_json::Map<_, _> =
+                (0..KEYS).map(|k| (format!("k{k}"), json!(0))).collect();
+            json!({ "type": "poll", "offsets": offsets })
+        }
+        "k-log" => json!({ "type": "send", "key": format!("k{}", rng.below(KEYS)), "msg": n }),
+        _ if rng.below(2) == 0 => json!({ "type": "add", "delta": rng.below(5) + 1 }),
+        _ => json!({ "type": "read" }),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let workload = args.next().unwrap_or_else(|| "k-log".to_string());
+    let fault = match args.next().as_deref() {
+        None | Some("kill") => Fault::Kill,
+        Some("stop") => Fault::Stop,
+        Some(other) => bail!("unknown fault {other}, expected kill or stop"),
+    };
+    let data_dir = match std::env::var(DATA_DIR_ENV) {
+        Ok(dir) => dir.into(),
+        Err(_) => std::env::temp_dir().join(format!("rustengan-{}", std::process::id())),
+    };
+    let binary = std::env::current_exe()?.with_file_name(&workload);
+    let node_ids = (0..NODES).map(|i| format!("n{i}")).collect();
+    // The detector moves a crashed k-log leader's keys to a live node.
+    let env = vec![
+        (DATA_DIR_ENV.to_string(), data_dir.display().to_string()),
+        (DETECTOR_ENV.to_string(), "1".to_string()),
+    ];
+    let mut cluster = Cluster::new(binary, node_ids, env)?;
+    match workload.as_str() {
+        "k-log" => cluster.check(OffsetsNeverReused::default()),
+        "g-counter" => cluster.check(NeverDecreasing::default()),
+        _ => bail!("unknown workload {workload}, expected k-log or g-counter"),
+    }
+
+    let mut rng = Rng::for_node("crash-restart");
+    let mut down: Option<String> = None;
+    let (mut answered, mut crashes) = (0, 0);
+    for n in 0..REQUESTS {
+        if n % CRASH_EVERY == CRASH_EVERY / 2 {
+            if let Some(node) = down.take() {
+                cluster.start(&node)?;
+            }
+        } else if n % CRASH_EVERY == 0 && n > 0 {
+            let node = rng.choose(cluster.node_ids()).cloned().unwrap_or_default();
+            cluster.crash(&node, fault)?;
+            down = Some(node);
+            crashes += 1;
+        }
+        let live: Vec<_> = cluster
+            .node_ids()
+            .iter()
+            .filter(|node| Some(*node) != down.as_ref())
+            .cloned()
+            .collect();
+        let node = rng.choose(&live).cloned().unwrap_or_default();
+        let payload = request(&workload, n, &mut rng);
+        if cluster.request(&node, payload)?.is_some() {
+            answered += 1;
+        }
+    }
+    let violations = cluster.violations().to_vec();
+    drop(cluster);
+    if std::env::var(DATA_DIR_ENV).is_err() {
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+
+    println!("{REQUESTS} requests, {answered} answered, {crashes} crashes ({fault:?})");
+    for violation in &violations {
+        println!("violation: {violation}");
+    }
+    if !violations.is_empty() {
+        bail!("{} violations", violations.len());
+    }
+    Ok(())
+}
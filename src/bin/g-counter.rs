@@ -1,4 +1,9 @@
-use rustengan::*;
+use rustengan::{
+    failure_detector::{FailureDetector, Suspicion},
+    membership::{self, HyParView},
+    storage::{self, Storage},
+    *,
+};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, io::StdoutLock, time::Duration};
 
@@ -11,7 +16,17 @@ enum Payload {
     Read,
     ReadOk { value: usize },
     Gossip { values: HashMap<String, usize> },
-    GossipOk { value: usize },
+    GossipOk { values: HashMap<String, usize> },
+    Heartbeat,
+    Membership(membership::Payload),
+}
+
+/// A node's counter reached `value`. Replaying these and keeping the maximum
+/// per node rebuilds the counters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Count {
+    node: String,
+    value: usize,
 }
 
 enum InjectedPayload {
@@ -23,7 +38,80 @@ struct GCounterNode {
     nodes: Vec<String>,
     id: usize,
     values: HashMap<String, usize>,
-    ack: HashMap<String, usize>,
+    /// What each peer is known to have, a lower bound on its counters.
+    ack: HashMap<String, HashMap<String, usize>>,
+    /// The last gossip sent to each peer, by message id.
+    sent: HashMap<String, (usize, HashMap<String, usize>)>,
+    membership: Option<HyParView>,
+    joined: bool,
+    detector: FailureDetector,
+    storage: Box<dyn Storage<Count>>,
+}
+
+impl GCounterNode {
+    /// Take the larger of ours and `values` for every counter, persisting
+    /// the ones that grew before they can be read.
+    fn merge(&mut self, values: &HashMap<String, usize>) -> anyhow::Result<()> {
+        for (k, v) in values {
+            if v > self.values.get(k).unwrap_or(&0) {
+                self.storage.append(&Count {
+                    node: k.clone(),
+                    value: *v,
+                })?;
+                self.values.insert(k.to_string(), *v);
+            }
+        }
+        Ok(())
+    }
+
+    /// Our counters that are ahead of `theirs`: what a peer holding `theirs`
+    /// is missing.
+    fn missing(&self, theirs: &HashMap<String, usize>) -> HashMap<String, usize> {
+        self.values
+            .iter()
+            .filter(|(k, v)| *v > theirs.get(*k).unwrap_or(&0))
+            .map(|(k, v)| (k.clone(), *v))
+            .collect()
+    }
+
+    /// Who to gossip with: the HyParView active view, or everybody.
+    fn peers(&self) -> Vec<String> {
+        match &self.membership {
+            Some(membership) => membership.active().cloned().collect(),
+            None => self.nodes.clone(),
+        }
+    }
+
+    fn send_to(
+        &mut self,
+        dest: String,
+        payload: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let message = Message {
+            src: self.node.clone(),
+            dest,
+            body: Body {
+                payload,
+                id: Some(self.id),
+                in_reply_to: None,
+            },
+        };
+        self.send(&message, output)?;
+        self.id += 1;
+        Ok(())
+    }
+
+    fn send_membership(
+        &mut self,
+        out: Vec<(String, membership::Payload)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for (dest, payload) in out {
+            self.send_to(dest, Payload::Membership(payload), output)?;
+        }
+        Ok(())
+    }
 }
 
 impl Node<Payload, InjectedPayload> for GCounterNode {
@@ -32,6 +120,11 @@ impl Node<Payload, InjectedPayload> for GCounterNode {
         event: Event<Payload, InjectedPayload>,
         output: &mut StdoutLock,
     ) -> anyhow::Result<()> {
+        if let Event::Message(input) = &event {
+            if self.nodes.contains(&input.src) {
+                self.detector.heartbeat(&input.src);
+            }
+        }
         match &event {
             Event::Message(input) => match &input.body.payload {
                 Payload::Read => {
@@ -44,56 +137,93 @@ impl Node<Payload, InjectedPayload> for GCounterNode {
                     self.send(&reply, output)?;
                 }
                 Payload::Add { delta } => {
-                    let value = self.values.entry(self.node.clone()).or_default();
-                    *value += delta;
+                    let value = self.values.get(&self.node).unwrap_or(&0) + delta;
+                    self.storage.append(&Count {
+                        node: self.node.clone(),
+                        value,
+                    })?;
+                    self.values.insert(self.node.clone(), value);
                     let reply = input.construct_reply(Payload::AddOk, Some(&mut self.id));
                     self.send(&reply, output)?;
                 }
                 Payload::Gossip { values } => {
-                    for k in &self.nodes {
-                        if let Some(v) = values.get(k) {
-                            if v > self.values.get(k).unwrap_or(&0) {
-                                self.values.insert(k.to_string(), *v);
-                            }
-                        }
-                        let reply = input.construct_reply(
-                            Payload::GossipOk {
-                                value: Clone::clone(self.values.get(&input.src).unwrap_or(&0)),
-                            },
-                            Some(&mut self.id),
-                        );
-                        self.send(&reply, output)?;
-                    }
+                    // Values are merged transitively, so a partial view is
+                    // enough for every counter to reach every node.
+                    // Reply with only what the sender lacks, the way a
+                    // broadcast digest is answered.
+                    self.merge(values)?;
+                    let reply = input.construct_reply(
+                        Payload::GossipOk {
+                            values: self.missing(values),
+                        },
+                        Some(&mut self.id),
+                    );
+                    self.send(&reply, output)?;
                 }
                 Payload::AddOk | Payload::ReadOk { .. } => {}
-                Payload::GossipOk { value } => {
-                    self.ack.insert(input.src.clone(), *value);
+                Payload::GossipOk { values } => {
+                    self.merge(values)?;
+                    // The peer has at least what it answered and, if this
+                    // answers our last gossip, everything that carried.
+                    let ack = self.ack.entry(input.src.clone()).or_default();
+                    let sent = self
+                        .sent
+                        .get(&input.src)
+                        .filter(|(id, _)| input.body.in_reply_to == Some(*id))
+                        .map(|(_, sent)| sent);
+                    for (k, v) in sent.into_iter().flatten().chain(values) {
+                        let known = ack.entry(k.clone()).or_default();
+                        *known = (*known).max(*v);
+                    }
+                }
+                Payload::Heartbeat => {}
+                Payload::Membership(payload) => {
+                    if let Some(membership) = &mut self.membership {
+                        let out = membership.handle(&input.src, payload);
+                        membership.take_changes();
+                        self.send_membership(out, output)?;
+                    }
                 }
             },
             Event::InjectedPayload(payload) => match payload {
                 InjectedPayload::Gossip => {
-                    for n in &self.nodes {
-                        if self.ack.get(n).unwrap_or(&0)
-                            != self.values.get(&self.node).unwrap_or(&0)
+                    self.storage.tick()?;
+                    if let Some(membership) = &mut self.membership {
+                        let out = if self.joined {
+                            membership.tick()
+                        } else {
+                            self.joined = true;
+                            let contact = self.nodes.iter().chain([&self.node]).min().cloned();
+                            membership.join(&contact.unwrap_or_default())
+                        };
+                        membership.take_changes();
+                        self.send_membership(out, output)?;
+                    }
+                    for suspicion in self.detector.poll() {
+                        if let (Suspicion::Suspected(peer), Some(membership)) =
+                            (suspicion, &mut self.membership)
                         {
-                            let message = Message {
-                                src: self.node.clone(),
-                                dest: n.to_string(),
-                                body: Body {
-                                    payload: Payload::Gossip {
-                                        values: self.values.clone(),
-                                    },
-                                    id: Some(self.id),
-                                    in_reply_to: None,
-                                },
-                            };
-                            self.send(&message, output)?;
-                            self.id += 1;
+                            let out = membership.peer_failed(&peer);
+                            membership.take_changes();
+                            self.send_membership(out, output)?;
+                        }
+                    }
+                    for n in self.peers() {
+                        if self.detector.is_enabled() {
+                            self.send_to(n.clone(), Payload::Heartbeat, output)?;
+                        }
+                        if !self.detector.is_alive(&n) {
+                            continue;
+                        }
+                        if self.ack.get(&n) != Some(&self.values) {
+                            let values = self.values.clone();
+                            self.sent.insert(n.clone(), (self.id, values.clone()));
+                            self.send_to(n, Payload::Gossip { values }, output)?;
                         }
                     }
                 }
             },
-            Event::EOF => {}
+            Event::EOF => self.storage.sync()?,
         }
         Ok(())
     }
@@ -117,7 +247,20 @@ impl Node<Payload, InjectedPayload> for GCounterNode {
                 }
             }
         });
-        let node = GCounterNode {
+        let membership = membership::enabled().then(|| {
+            HyParView::new(
+                &init.node_id,
+                &init.node_ids,
+                membership::Config::for_cluster(init.node_ids.len()),
+            )
+        });
+        // HyParView repairs its view off the detector's suspicions.
+        let detector = match membership {
+            Some(_) => FailureDetector::default(),
+            None => FailureDetector::from_env(),
+        };
+        let storage = storage::open(&init.node_id, "g-counter")?;
+        let mut node = GCounterNode {
             id: 1,
             values: HashMap::new(),
             nodes: init
@@ -127,7 +270,16 @@ impl Node<Payload, InjectedPayload> for GCounterNode {
                 .collect(),
             node: init.node_id,
             ack: HashMap::new(),
+            sent: HashMap::new(),
+            membership,
+            joined: false,
+            detector,
+            storage,
         };
+        for count in node.storage.replay()? {
+            let value = node.values.entry(count.node).or_default();
+            *value = count.value.max(*value);
+        }
         Ok(node)
     }
 }
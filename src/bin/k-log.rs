@@ -1,13 +1,43 @@
+use anyhow::{bail, Context};
 use regex::Regex;
-use rustengan::*;
+use rustengan::{
+    digest::RangeSet,
+    election::{self, Bully, Election, Lease},
+    failure_detector::FailureDetector,
+    kv,
+    retry::InFlight,
+    rng::Rng,
+    segment::{self, SegmentedLog},
+    storage::{self, Storage},
+    *,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::max,
-    collections::{BTreeMap, HashMap},
-    io::StdoutLock,
+    collections::{HashMap, HashSet},
+    io::{StdoutLock, Write},
     time::Duration,
 };
 
+const REPLICATE_TIMEOUT: Duration = Duration::from_millis(300);
+const REPLICATE_ATTEMPTS: usize = 10;
+const IN_FLIGHT_CAPACITY: usize = 4096;
+/// Offset CASes tried for one send before it fails.
+const CLAIM_ATTEMPTS: usize = 10;
+/// Custom error code for polls below what retention or compaction kept.
+const OFFSET_OUT_OF_RANGE: isize = 1000;
+const POLL_KEY_LIMIT_ENV: &str = "RUSTENGAN_POLL_KEY_LIMIT";
+const POLL_LIMIT_ENV: &str = "RUSTENGAN_POLL_LIMIT";
+const LEADER_ENV: &str = "RUSTENGAN_LEADER";
+/// lin-kv key holding the leader lease.
+const LEASE_KEY: &str = "k-log/leader";
+const LEASE_TTL: Duration = Duration::from_secs(1);
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+/// Every this many ticks, swap log digests with the next peer in turn.
+const SYNC_EVERY: usize = 10;
+/// Most entries one `SyncOk` carries; the rest follow in later rounds.
+const SYNC_BATCH: usize = 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -24,6 +54,9 @@ enum Payload {
     },
     PollOk {
         msgs: HashMap<String, Vec<(usize, usize)>>,
+        /// Where to continue polling keys whose messages did not all fit.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        next_offsets: HashMap<String, usize>,
     },
     CommitOffsets {
         offsets: HashMap<String, usize>,
@@ -43,39 +76,500 @@ enum Payload {
         put: bool,
     },
     CasOk,
+    /// lin-kv's answer to the leader lease's reads.
+    ReadOk {
+        value: serde_json::Value,
+    },
     Error {
         code: isize,
         text: String,
+        /// For [`OFFSET_OUT_OF_RANGE`], the earliest offset each key still has.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        earliest_offsets: HashMap<String, usize>,
     },
-    Gossip {
+    /// An entry the key's leader appended, copied to every follower.
+    Replicate {
+        key: String,
         offset: usize,
+        msg: usize,
+    },
+    /// Committed consumer offsets, copied to every node.
+    ReplicateCommits {
+        offsets: HashMap<String, usize>,
     },
+    ReplicateOk,
+    /// Anti-entropy: where each of our logs starts and which offsets it
+    /// holds from there, plus our committed offsets. Replication gives up
+    /// on followers that stay unreachable, this is how they catch up.
+    Sync {
+        logs: HashMap<String, (usize, RangeSet)>,
+        committed: HashMap<String, usize>,
+    },
+    /// The entries the syncing node lacks, as `(key, offset, msg)`, and our
+    /// committed offsets.
+    SyncOk {
+        entries: Vec<(String, usize, usize)>,
+        committed: HashMap<String, usize>,
+    },
+    Heartbeat,
+    Election(election::Payload),
+}
+
+/// Caps on how many messages one `poll` returns, set with
+/// `RUSTENGAN_POLL_KEY_LIMIT` and `RUSTENGAN_POLL_LIMIT`.
+struct PollLimits {
+    per_key: usize,
+    per_response: usize,
+}
+
+impl PollLimits {
+    fn from_env() -> anyhow::Result<Self> {
+        let limit = |var: &str, default: usize| match std::env::var(var) {
+            Ok(s) => s
+                .parse::<usize>()
+                .with_context(|| format!("invalid {var}: {s}")),
+            Err(_) => Ok(default),
+        };
+        Ok(PollLimits {
+            per_key: limit(POLL_KEY_LIMIT_ENV, 100)?,
+            per_response: limit(POLL_LIMIT_ENV, 1000)?,
+        })
+    }
 }
 
 enum InjectedPayload {
-    CasRetry,
     Gossip,
 }
 
+/// How keys get their leader, set with `RUSTENGAN_LEADER`.
+enum Leadership {
+    /// Per key, by rendezvous hashing over the live nodes (`hash`, the
+    /// default).
+    Hashed,
+    /// One leader for every key, elected in the cluster (`bully`).
+    Bully(Bully),
+    /// One leader for every key, holding a lease in lin-kv (`lease`).
+    Lease(Lease),
+}
+
+impl Leadership {
+    fn from_env(me: &str, node_ids: &[String]) -> anyhow::Result<Self> {
+        match std::env::var(LEADER_ENV).as_deref() {
+            Err(_) | Ok("hash") => Ok(Leadership::Hashed),
+            Ok("bully") => Ok(Leadership::Bully(Bully::new(
+                me,
+                node_ids,
+                ELECTION_TIMEOUT,
+            ))),
+            Ok("lease") => Ok(Leadership::Lease(Lease::new(me, LEASE_KEY, LEASE_TTL))),
+            Ok(other) => bail!("unknown leadership {other}, expected hash, bully or lease"),
+        }
+    }
+
+    fn election(&self) -> Option<&dyn Election> {
+        match self {
+            Leadership::Hashed => None,
+            Leadership::Bully(bully) => Some(bully),
+            Leadership::Lease(lease) => Some(lease),
+        }
+    }
+}
+
+/// What k-log persists: log entries and committed offsets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Entry {
+        key: String,
+        offset: usize,
+        msg: usize,
+    },
+    Commit {
+        offsets: HashMap<String, usize>,
+    },
+    /// Retention or compaction dropped the key's offsets below `start`.
+    Truncate {
+        key: String,
+        start: usize,
+    },
+}
+
+/// Who to answer once a send is done: the client, or the node that
+/// forwarded the send to us.
+#[derive(Debug, Clone)]
+struct Requester {
+    src: String,
+    msg_id: Option<usize>,
+}
+
+/// A send at the key's leader, waiting for its offset from lin-kv.
+struct PendingSend {
+    key: String,
+    msg: usize,
+    offset: usize,
+    /// CASes sent for it so far.
+    attempts: usize,
+    requester: Requester,
+}
+
 struct KLogNode {
     node: String,
     nodes: Vec<String>,
     id: usize,
-    logs: HashMap<String, BTreeMap<usize, usize>>,
+    logs: HashMap<String, SegmentedLog<usize>>,
+    log_config: segment::Config,
     processed_till: HashMap<String, usize>,
-    curr_offset: usize,
-    logs_to_process: HashMap<usize, LogToProcess>,
-    known_offsets: HashMap<String, usize>,
+    /// Last offset handed out per key, as far as we know.
+    curr_offsets: HashMap<String, usize>,
+    /// Sends waiting for their offset CAS, by the CAS's msg id.
+    pending: HashMap<usize, PendingSend>,
+    /// Sends we forwarded to the key's leader, by the forward's msg id.
+    forwarded: HashMap<usize, Requester>,
+    /// Replication messages to followers, tagged with the request they
+    /// belong to.
+    replicating: InFlight<(usize, Payload)>,
+    /// Requests waiting for their followers, with the reply to send then.
+    unreplicated: HashMap<usize, (Requester, Payload)>,
+    requests: usize,
+    detector: FailureDetector,
+    leadership: Leadership,
+    ticks: usize,
+    poll_limits: PollLimits,
+    storage: Box<dyn Storage<Record>>,
 }
 
-struct LogToProcess {
-    key: String,
-    msg: usize,
-    request_src: String,
-    offset: Option<usize>,
-    request_msg_id: Option<usize>,
-    cas_msg_id: usize,
-    cas_failed: bool,
+impl KLogNode {
+    /// The key's leader: the live node ranked highest for the key by
+    /// rendezvous hashing. Every node computes the same ranking, and when the
+    /// leader dies its keys move to the next node in line. With an election,
+    /// the elected node leads every key.
+    fn leader_for(&self, key: &str) -> String {
+        if let Some(election) = self.leadership.election() {
+            // While nobody is known to lead we take sends ourselves; the
+            // offset CAS keeps them apart from the next leader's.
+            return election.leader().unwrap_or(&self.node).to_string();
+        }
+        self.nodes
+            .iter()
+            .filter(|n| self.detector.is_alive(n))
+            .chain([&self.node])
+            .max_by_key(|n| Rng::from_hash(&(key, n.as_str())).next_u64())
+            .cloned()
+            .unwrap_or_else(|| self.node.clone())
+    }
+
+    fn send_to(
+        &mut self,
+        dest: String,
+        payload: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<usize> {
+        let id = self.id;
+        let message = Message {
+            src: self.node.clone(),
+            dest,
+            body: Body {
+                payload,
+                id: Some(id),
+                in_reply_to: None,
+            },
+        };
+        self.send(&message, output)?;
+        self.id += 1;
+        Ok(id)
+    }
+
+    /// Send a request the leader lease built; it speaks [`kv::Payload`],
+    /// not ours.
+    fn send_kv(
+        &self,
+        message: &Message<kv::Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut *output, message).context("serialize lease request")?;
+        output.write_all(b"\n").context("write trailing newline")?;
+        Ok(())
+    }
+
+    fn send_election(
+        &mut self,
+        out: Vec<(String, election::Payload)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for (dest, payload) in out {
+            self.send_to(dest, Payload::Election(payload), output)?;
+        }
+        Ok(())
+    }
+
+    /// Hand a lin-kv reply to the lease if it is waiting for it. Returns
+    /// whether it was.
+    fn lease_reply(
+        &mut self,
+        input: &Message<Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<bool> {
+        let Leadership::Lease(lease) = &mut self.leadership else {
+            return Ok(false);
+        };
+        if !lease.owns(input.body.in_reply_to) {
+            return Ok(false);
+        }
+        let reply = match &input.body.payload {
+            Payload::ReadOk { value } => kv::Payload::ReadOk {
+                value: value.clone(),
+            },
+            Payload::CasOk => kv::Payload::CasOk,
+            Payload::Error { code, text, .. } => kv::Payload::Error {
+                code: *code,
+                text: text.clone(),
+            },
+            _ => return Ok(false),
+        };
+        if let Some(request) = lease.handle(input.body.in_reply_to, &reply, &mut self.id) {
+            self.send_kv(&request, output)?;
+        }
+        Ok(true)
+    }
+
+    fn reply_to(
+        &mut self,
+        requester: Requester,
+        payload: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let message = Message {
+            src: self.node.clone(),
+            dest: requester.src,
+            body: Body {
+                payload,
+                id: Some(self.id),
+                in_reply_to: requester.msg_id,
+            },
+        };
+        self.send(&message, output)?;
+        self.id += 1;
+        Ok(())
+    }
+
+    /// Claim the key's next offset with a CAS on its counter in lin-kv. The
+    /// leader normally wins it first time; the CAS only matters while a
+    /// leader change is in flux. After [`CLAIM_ATTEMPTS`] the send fails
+    /// with a temporarily-unavailable error instead.
+    fn claim_offset(
+        &mut self,
+        mut send: PendingSend,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        if send.attempts >= CLAIM_ATTEMPTS {
+            let payload = Payload::Error {
+                code: kv::TEMPORARILY_UNAVAILABLE,
+                text: format!("could not claim an offset for {}", send.key),
+                earliest_offsets: HashMap::new(),
+            };
+            return self.reply_to(send.requester, payload, output);
+        }
+        send.attempts += 1;
+        let curr_offset = self.curr_offsets.entry(send.key.clone()).or_default();
+        send.offset = *curr_offset + 1;
+        let payload = Payload::Cas {
+            key: format!("offset/{}", send.key),
+            from: *curr_offset,
+            to: send.offset,
+            put: true,
+        };
+        *curr_offset += 1;
+        let id = self.send_to("lin-kv".to_string(), payload, output)?;
+        self.pending.insert(id, send);
+        Ok(())
+    }
+
+    /// Write `record` to storage before it takes effect, so it is there to
+    /// replay after a restart.
+    fn persist(&mut self, record: Record) -> anyhow::Result<()> {
+        self.storage.append(&record)?;
+        self.apply(record);
+        Ok(())
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Entry { key, offset, msg } => {
+                self.logs
+                    .entry(key.clone())
+                    .or_insert_with(|| SegmentedLog::new(self.log_config.segment_size))
+                    .insert(offset, msg);
+                let curr_offset = self.curr_offsets.entry(key).or_default();
+                *curr_offset = max(*curr_offset, offset);
+            }
+            // Written first for its key in a checkpoint, so the entries after
+            // it that were appended before the drop are not brought back.
+            Record::Truncate { key, start } => {
+                self.logs
+                    .entry(key)
+                    .or_insert_with(|| SegmentedLog::new(self.log_config.segment_size))
+                    .truncate(start);
+            }
+            // Committed offsets only move forward, so replicas can merge
+            // commits in any order and a late or repeated one never rolls a
+            // consumer back.
+            Record::Commit { offsets } => {
+                for (key, offset) in offsets {
+                    let committed = self.processed_till.entry(key).or_default();
+                    *committed = max(*committed, offset);
+                }
+            }
+        }
+    }
+
+    /// Rewrite storage as the state we hold now. Called after dropping
+    /// segments, so the WAL shrinks with the logs instead of keeping every
+    /// entry ever appended.
+    fn checkpoint(&mut self) -> anyhow::Result<()> {
+        let mut records = Vec::new();
+        for (key, log) in &self.logs {
+            records.push(Record::Truncate {
+                key: key.clone(),
+                start: log.start(),
+            });
+            records.extend(
+                log.range_from(log.start())
+                    .map(|(offset, msg)| Record::Entry {
+                        key: key.clone(),
+                        offset: *offset,
+                        msg: *msg,
+                    }),
+            );
+        }
+        records.push(Record::Commit {
+            offsets: self.processed_till.clone(),
+        });
+        self.storage.rewrite(&records)
+    }
+
+    /// Persist an entry another node sent, unless we have it or dropped it
+    /// already.
+    fn learn(&mut self, key: String, offset: usize, msg: usize) -> anyhow::Result<()> {
+        let known = self
+            .logs
+            .get(&key)
+            .is_some_and(|log| offset < log.start() || log.get(offset).is_some());
+        if known {
+            return Ok(());
+        }
+        self.persist(Record::Entry { key, offset, msg })
+    }
+
+    /// Persist the committed offsets that are ahead of ours.
+    fn learn_commits(&mut self, offsets: &HashMap<String, usize>) -> anyhow::Result<()> {
+        let ahead: HashMap<String, usize> = offsets
+            .iter()
+            .filter(|(key, offset)| self.processed_till.get(*key) < Some(offset))
+            .map(|(key, offset)| (key.clone(), *offset))
+            .collect();
+        if ahead.is_empty() {
+            return Ok(());
+        }
+        self.persist(Record::Commit { offsets: ahead })
+    }
+
+    fn digest(&self) -> HashMap<String, (usize, RangeSet)> {
+        self.logs
+            .iter()
+            .map(|(key, log)| {
+                let offsets = log.range_from(log.start()).map(|(offset, _)| *offset);
+                (key.clone(), (log.start(), offsets.collect()))
+            })
+            .collect()
+    }
+
+    /// Our entries that a peer with `digest` lacks.
+    fn missing(&self, digest: &HashMap<String, (usize, RangeSet)>) -> Vec<(String, usize, usize)> {
+        let mut entries = Vec::new();
+        for (key, log) in &self.logs {
+            let (start, held) = match digest.get(key) {
+                Some((start, held)) => (*start, held.clone()),
+                None => (0, RangeSet::new()),
+            };
+            let from = max(start, log.start());
+            for (offset, msg) in log.range_from(from) {
+                if entries.len() >= SYNC_BATCH {
+                    return entries;
+                }
+                if !held.contains(*offset) {
+                    entries.push((key.clone(), *offset, *msg));
+                }
+            }
+        }
+        entries
+    }
+
+    fn append(&mut self, send: PendingSend, output: &mut StdoutLock) -> anyhow::Result<()> {
+        self.persist(Record::Entry {
+            key: send.key.clone(),
+            offset: send.offset,
+            msg: send.msg,
+        })?;
+        let payload = Payload::Replicate {
+            key: send.key,
+            offset: send.offset,
+            msg: send.msg,
+        };
+        let reply = Payload::SendOk {
+            offset: send.offset,
+        };
+        self.replicate(payload, send.requester, reply, output)
+    }
+
+    /// Copy `payload` to every live follower and answer `requester` with
+    /// `reply` once they all have it.
+    fn replicate(
+        &mut self,
+        payload: Payload,
+        requester: Requester,
+        reply: Payload,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let request = self.requests;
+        self.requests += 1;
+        let followers: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|n| self.detector.is_alive(n))
+            .cloned()
+            .collect();
+        for follower in followers {
+            let id = self.send_to(follower.clone(), payload.clone(), output)?;
+            self.replicating
+                .track(id, follower, (request, payload.clone()));
+        }
+        self.unreplicated.insert(request, (requester, reply));
+        self.ack_replicated(output)
+    }
+
+    /// Answer requests every live follower has confirmed. Followers that
+    /// died or ran out of retries are not waited for.
+    fn ack_replicated(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let waiting: HashSet<usize> = self
+            .nodes
+            .iter()
+            .filter(|n| self.detector.is_alive(n))
+            .flat_map(|n| self.replicating.to(n).map(|(request, _)| *request))
+            .collect();
+        let done: Vec<usize> = self
+            .unreplicated
+            .keys()
+            .filter(|request| !waiting.contains(request))
+            .copied()
+            .collect();
+        for request in done {
+            if let Some((requester, reply)) = self.unreplicated.remove(&request) {
+                self.reply_to(requester, reply, output)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Node<Payload, InjectedPayload> for KLogNode {
@@ -84,61 +578,109 @@ impl Node<Payload, InjectedPayload> for KLogNode {
         event: Event<Payload, InjectedPayload>,
         output: &mut StdoutLock,
     ) -> anyhow::Result<()> {
+        if let Event::Message(input) = &event {
+            if self.nodes.contains(&input.src) {
+                self.detector.heartbeat(&input.src);
+            }
+        }
+        if let Event::Message(input) = &event {
+            if input.src == kv::LIN_KV && self.lease_reply(input, output)? {
+                return Ok(());
+            }
+        }
         match &event {
             Event::Message(input) => match &input.body.payload {
                 Payload::Send { key, msg } => {
-                    let log_details = LogToProcess {
-                        key: key.clone(),
-                        msg: msg.clone(),
-                        request_src: input.src.clone(),
-                        offset: Some(self.curr_offset + 1),
-                        request_msg_id: input.body.id,
-                        cas_msg_id: self.id,
-                        cas_failed: false,
-                    };
-                    self.logs_to_process.insert(self.id, log_details);
-                    let payload = Payload::Cas {
-                        key: "offset".to_string(),
-                        from: self.curr_offset,
-                        to: self.curr_offset + 1,
-                        put: true,
+                    let requester = Requester {
+                        src: input.src.clone(),
+                        msg_id: input.body.id,
                     };
-                    let mut reply = input.construct_reply(payload, Some(&mut self.id));
-                    reply.body.in_reply_to = None;
-                    reply.dest = "lin-kv".to_string();
-                    self.send(&reply, output)?;
-                    self.curr_offset += 1;
+                    let leader = self.leader_for(key);
+                    // Forwarded sends are taken as they come, so two nodes
+                    // briefly disagreeing on the leader cannot bounce a send
+                    // around; offsets stay unique through the CAS either way.
+                    if leader == self.node || self.nodes.contains(&input.src) {
+                        let send = PendingSend {
+                            key: key.clone(),
+                            msg: *msg,
+                            offset: 0,
+                            attempts: 0,
+                            requester,
+                        };
+                        self.claim_offset(send, output)?;
+                    } else {
+                        let payload = Payload::Send {
+                            key: key.clone(),
+                            msg: *msg,
+                        };
+                        let id = self.send_to(leader, payload, output)?;
+                        self.forwarded.insert(id, requester);
+                    }
+                }
+                Payload::SendOk { offset } => {
+                    let forwarded = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.forwarded.remove(&id));
+                    if let Some(requester) = forwarded {
+                        self.reply_to(requester, Payload::SendOk { offset: *offset }, output)?;
+                    }
                 }
                 Payload::Poll { offsets } => {
                     let mut msgs: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+                    let mut next_offsets: HashMap<String, usize> = HashMap::new();
+                    let mut earliest_offsets: HashMap<String, usize> = HashMap::new();
+                    let mut budget = self.poll_limits.per_response;
                     for (key, offset) in offsets.iter() {
-                        let mut msgs_after_offset: Vec<(usize, usize)> = Vec::new();
-                        if let Some(log) = self.logs.get(key) {
-                            for (log_offset, msg) in log.iter() {
-                                if log_offset >= offset {
-                                    msgs_after_offset.push((log_offset.clone(), msg.clone()));
-                                }
-                            }
-                            msgs.insert(key.to_string(), msgs_after_offset);
+                        let Some(log) = self.logs.get(key) else {
+                            continue;
+                        };
+                        if *offset < log.start() {
+                            earliest_offsets.insert(key.to_string(), log.start());
+                            continue;
+                        }
+                        let mut range = log.range_from(*offset);
+                        let msgs_after_offset: Vec<(usize, usize)> = range
+                            .by_ref()
+                            .take(self.poll_limits.per_key.min(budget))
+                            .map(|(offset, msg)| (*offset, *msg))
+                            .collect();
+                        if let Some((next, _)) = range.next() {
+                            next_offsets.insert(key.to_string(), *next);
                         }
+                        budget -= msgs_after_offset.len();
+                        msgs.insert(key.to_string(), msgs_after_offset);
                     }
-                    let reply = input.construct_reply(Payload::PollOk { msgs }, Some(&mut self.id));
+                    let payload = if earliest_offsets.is_empty() {
+                        Payload::PollOk { msgs, next_offsets }
+                    } else {
+                        Payload::Error {
+                            code: OFFSET_OUT_OF_RANGE,
+                            text: "offset out of range".to_string(),
+                            earliest_offsets,
+                        }
+                    };
+                    let reply = input.construct_reply(payload, Some(&mut self.id));
                     self.send(&reply, output)?;
                 }
                 Payload::CommitOffsets { offsets } => {
-                    for (key, offset) in offsets.iter() {
-                        if let Some(_log) = self.logs.get(key) {
-                            self.processed_till.insert(key.to_string(), offset.clone());
-                        }
-                    }
-                    let reply = input.construct_reply(Payload::CommitOffsetsOk, Some(&mut self.id));
-                    self.send(&reply, output)?;
+                    self.persist(Record::Commit {
+                        offsets: offsets.clone(),
+                    })?;
+                    let requester = Requester {
+                        src: input.src.clone(),
+                        msg_id: input.body.id,
+                    };
+                    let payload = Payload::ReplicateCommits {
+                        offsets: offsets.clone(),
+                    };
+                    self.replicate(payload, requester, Payload::CommitOffsetsOk, output)?;
                 }
                 Payload::ListCommittedOffsets { keys } => {
                     let mut commited_offsets: HashMap<String, usize> = HashMap::new();
                     for key in keys.iter() {
                         if let Some(commited_offset) = self.processed_till.get(key) {
-                            commited_offsets.insert(key.to_string(), commited_offset.clone());
+                            commited_offsets.insert(key.to_string(), *commited_offset);
                         }
                     }
                     let reply = input.construct_reply(
@@ -150,119 +692,152 @@ impl Node<Payload, InjectedPayload> for KLogNode {
                     self.send(&reply, output)?;
                 }
                 Payload::CasOk => {
-                    let cas_msg_id = &input.body.in_reply_to.unwrap_or(0);
-                    if let Some(log_details) = self.logs_to_process.get(cas_msg_id) {
-                        let key = log_details.key.clone();
-                        let send_msg_scr = log_details.request_src.clone();
-                        let msg = log_details.msg;
-                        let log = self.logs.entry(key).or_insert(BTreeMap::new());
-                        if let Some(offset) = log_details.offset {
-                            log.insert(offset, msg);
-                            let payload = Payload::SendOk { offset };
-                            let mut send_ok_response =
-                                input.construct_reply(payload, Some(&mut self.id));
-                            send_ok_response.body.in_reply_to = log_details.request_msg_id;
-                            send_ok_response.dest = send_msg_scr;
-                            self.send(&send_ok_response, output)?;
-                            self.logs_to_process.remove(&cas_msg_id);
-                        }
+                    let pending = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.pending.remove(&id));
+                    if let Some(send) = pending {
+                        self.append(send, output)?;
                     }
                 }
-                Payload::Error { code, text } => match code {
-                    20 => {
-                        let payload = Payload::Cas {
-                            key: "offset".to_string(),
-                            from: 0,
-                            to: 0,
-                            put: true,
-                        };
-                        let mut message = input.construct_reply(payload, Some(&mut self.id));
-                        message.body.in_reply_to = None;
-                        self.send(&message, output)?;
+                Payload::Error { code, text, .. } => {
+                    // A send we forwarded that failed at the leader.
+                    let forwarded = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.forwarded.remove(&id));
+                    if let Some(requester) = forwarded {
+                        let payload = input.body.payload.clone();
+                        return self.reply_to(requester, payload, output);
                     }
-                    22 => {
-                        let regex =
-                            Regex::new(r"current value (?<new>\d+) is not (?<old>\d+)").unwrap();
-                        if let Some(caps) = regex.captures(text) {
-                            let new_val = usize::from_str_radix(&caps["new"], 10).unwrap();
-                            self.curr_offset = new_val;
-                            if let Some(log_details) = self
-                                .logs_to_process
-                                .get_mut(&input.body.in_reply_to.unwrap_or(usize::MAX))
-                            {
-                                log_details.cas_failed = true;
+                    let pending = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.pending.remove(&id));
+                    if let Some(send) = pending {
+                        if *code == 22 {
+                            let regex = Regex::new(r"current value (?<new>\d+) is not (?<old>\d+)")
+                                .unwrap();
+                            if let Some(caps) = regex.captures(text) {
+                                let new_val = caps["new"].parse::<usize>().unwrap();
+                                // lin-kv's counter is the truth: with CASes in
+                                // flight and replies out of order, ours can be
+                                // ahead of it as well as behind.
+                                self.curr_offsets.insert(send.key.clone(), new_val);
                             }
                         }
+                        // Someone else took the offset; try the next one.
+                        self.claim_offset(send, output)?;
                     }
-                    _ => {}
-                },
-                Payload::Gossip { offset } => {
-                    self.curr_offset = max(self.curr_offset, offset.clone());
-                    // self.known_offsets.insert(input.src.clone(), offset.clone());
-                    let known_offset = self.known_offsets.entry(input.src.clone()).or_insert(0);
-                    *known_offset = *offset;
                 }
-                Payload::SendOk { .. }
-                | Payload::PollOk { .. }
+                Payload::Replicate { key, offset, msg } => {
+                    self.learn(key.clone(), *offset, *msg)?;
+                    let reply = input.construct_reply(Payload::ReplicateOk, Some(&mut self.id));
+                    self.send(&reply, output)?;
+                }
+                Payload::ReplicateCommits { offsets } => {
+                    self.learn_commits(offsets)?;
+                    let reply = input.construct_reply(Payload::ReplicateOk, Some(&mut self.id));
+                    self.send(&reply, output)?;
+                }
+                Payload::ReplicateOk => {
+                    let replicated = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.replicating.ack(id));
+                    if replicated.is_some() {
+                        self.ack_replicated(output)?;
+                    }
+                }
+                Payload::Sync { logs, committed } => {
+                    self.learn_commits(committed)?;
+                    let payload = Payload::SyncOk {
+                        entries: self.missing(logs),
+                        committed: self.processed_till.clone(),
+                    };
+                    let reply = input.construct_reply(payload, Some(&mut self.id));
+                    self.send(&reply, output)?;
+                }
+                Payload::SyncOk { entries, committed } => {
+                    for (key, offset, msg) in entries {
+                        self.learn(key.clone(), *offset, *msg)?;
+                    }
+                    self.learn_commits(committed)?;
+                }
+                Payload::PollOk { .. }
                 | Payload::CommitOffsetsOk
                 | Payload::ListCommittedOffsetsOk { .. }
-                | Payload::Cas { .. } => {}
-            },
-            Event::InjectedPayload(injected_payload) => match &injected_payload {
-                InjectedPayload::CasRetry => {
-                    for (_key, log_details) in self.logs_to_process.iter_mut() {
-                        if !log_details.cas_failed {
-                            continue;
-                        }
-                        let payload = Payload::Cas {
-                            key: "offset".to_string(),
-                            from: self.curr_offset,
-                            to: self.curr_offset + 1,
-                            put: true,
-                        };
-                        let message = Message {
-                            src: self.node.to_string(),
-                            dest: "lin-kv".to_string(),
-                            body: Body {
-                                payload,
-                                id: Some(self.id),
-                                in_reply_to: None,
-                            },
-                        };
-                        self.curr_offset += 1;
-                        log_details.cas_msg_id = self.id;
-                        log_details.cas_failed = false;
-                        self.id += 1;
-                        self.send(&message, output)?;
-                        break;
+                | Payload::Cas { .. }
+                | Payload::ReadOk { .. }
+                | Payload::Heartbeat => {}
+                Payload::Election(payload) => {
+                    if let Leadership::Bully(bully) = &mut self.leadership {
+                        let out = bully.handle(&input.src, payload);
+                        self.send_election(out, output)?;
                     }
                 }
+            },
+            Event::InjectedPayload(injected_payload) => match &injected_payload {
                 InjectedPayload::Gossip => {
-                    for node in self.nodes.iter() {
-                        if let Some(known_offset) = self.known_offsets.get(node) {
-                            if *known_offset >= self.curr_offset {
-                                self.curr_offset = *known_offset;
-                                continue;
+                    self.storage.tick()?;
+                    match &mut self.leadership {
+                        Leadership::Hashed => {}
+                        Leadership::Bully(bully) => {
+                            let out = bully.tick(&self.detector);
+                            // Sends follow whoever leads at the time, there
+                            // is nothing to hand over.
+                            bully.take_changes();
+                            self.send_election(out, output)?;
+                        }
+                        Leadership::Lease(lease) => {
+                            let request = lease.tick(&mut self.id);
+                            lease.take_changes();
+                            if let Some(request) = request {
+                                self.send_kv(&request, output)?;
                             }
                         }
-                        let payload = Payload::Gossip {
-                            offset: self.curr_offset,
-                        };
-                        let message = Message {
-                            src: self.node.to_string(),
-                            dest: node.to_string(),
-                            body: Body {
-                                payload,
-                                id: Some(self.id),
-                                in_reply_to: None,
-                            },
-                        };
-                        self.id += 1;
-                        self.send(&message, output)?;
+                    }
+                    for entry in self.replicating.expired() {
+                        let payload = entry.data.1.clone();
+                        let id = self.send_to(entry.dest.clone(), payload, output)?;
+                        self.replicating.retry(id, entry);
+                    }
+                    self.ack_replicated(output)?;
+                    self.ticks += 1;
+                    if self.ticks.is_multiple_of(SYNC_EVERY) && !self.nodes.is_empty() {
+                        let peer = &self.nodes[self.ticks / SYNC_EVERY % self.nodes.len()];
+                        if self.detector.is_alive(peer) {
+                            let payload = Payload::Sync {
+                                logs: self.digest(),
+                                committed: self.processed_till.clone(),
+                            };
+                            self.send_to(peer.clone(), payload, output)?;
+                        }
+                    }
+                    let mut dropped = false;
+                    for (key, log) in self.logs.iter_mut() {
+                        let start = log.start();
+                        log.retain(&self.log_config);
+                        if let (true, Some(committed)) =
+                            (self.log_config.compact, self.processed_till.get(key))
+                        {
+                            log.compact(*committed);
+                        }
+                        dropped |= log.start() != start;
+                    }
+                    if dropped {
+                        self.checkpoint()?;
+                    }
+                    // Followers learn the offsets from replication, the
+                    // heartbeats only keep the failure detector fed.
+                    if self.detector.is_enabled() {
+                        for node in self.nodes.clone() {
+                            self.send_to(node, Payload::Heartbeat, output)?;
+                        }
                     }
                 }
             },
-            Event::EOF => {}
+            Event::EOF => self.storage.sync()?,
         }
         Ok(())
     }
@@ -278,12 +853,6 @@ impl Node<Payload, InjectedPayload> for KLogNode {
             // TODO: Handle EOF
             loop {
                 std::thread::sleep(Duration::from_millis(100));
-                if tx
-                    .send(Event::InjectedPayload(InjectedPayload::CasRetry))
-                    .is_err()
-                {
-                    break;
-                }
                 if tx
                     .send(Event::InjectedPayload(InjectedPayload::Gossip))
                     .is_err()
@@ -292,9 +861,18 @@ impl Node<Payload, InjectedPayload> for KLogNode {
                 }
             }
         });
-        let node = KLogNode {
+        let storage = storage::open(&init.node_id, "k-log")?;
+        let leadership = Leadership::from_env(&init.node_id, &init.node_ids)?;
+        // Bully only replaces a leader it suspects, so it always needs
+        // heartbeats whatever the environment says.
+        let detector = match leadership {
+            Leadership::Bully(_) => FailureDetector::default(),
+            _ => FailureDetector::from_env(),
+        };
+        let mut node = KLogNode {
             id: 1,
             logs: HashMap::new(),
+            log_config: segment::Config::from_env()?,
             processed_till: HashMap::new(),
             nodes: init
                 .node_ids
@@ -302,10 +880,21 @@ impl Node<Payload, InjectedPayload> for KLogNode {
                 .filter(|n| n != &init.node_id)
                 .collect(),
             node: init.node_id,
-            logs_to_process: HashMap::new(),
-            curr_offset: 0,
-            known_offsets: HashMap::new(),
+            curr_offsets: HashMap::new(),
+            pending: HashMap::new(),
+            forwarded: HashMap::new(),
+            replicating: InFlight::new(REPLICATE_TIMEOUT, REPLICATE_ATTEMPTS, IN_FLIGHT_CAPACITY),
+            unreplicated: HashMap::new(),
+            requests: 0,
+            detector,
+            leadership,
+            ticks: 0,
+            poll_limits: PollLimits::from_env()?,
+            storage,
         };
+        for record in node.storage.replay()? {
+            node.apply(record);
+        }
         Ok(node)
     }
 }
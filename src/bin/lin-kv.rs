@@ -0,0 +1,271 @@
+use rustengan::{
+    consensus::{Consensus, Proposal, CONSENSUS_ENV},
+    kv::{self, Store},
+    paxos::{self, Paxos},
+    raft::{self, Raft},
+    *,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{io::StdoutLock, time::Duration};
+
+/// Client requests and replies are plain `lin-kv` messages; everything the
+/// nodes say to each other is tagged separately.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Payload<P> {
+    Kv(kv::Payload),
+    Internal(Internal<P>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Internal<P> {
+    Consensus(P),
+    /// A client request handed to the node we believe is leader, `hops`
+    /// times so far.
+    Forward {
+        proposal: Proposal<kv::Payload>,
+        #[serde(default)]
+        hops: usize,
+    },
+}
+
+enum InjectedPayload {
+    Tick,
+}
+
+/// Forwards a request may take before it fails with error 11, so nodes with
+/// stale views of the leader cannot pass it around in a cycle forever.
+const MAX_HOPS: usize = 3;
+
+/// Consensus implementations the node can run, picked with
+/// `RUSTENGAN_CONSENSUS=raft` (default) or `paxos`.
+trait Replicated: Consensus<Store, Payload: Serialize + DeserializeOwned + Send + 'static> {
+    fn new(init: &Init) -> Self;
+}
+
+impl Replicated for Raft<Store> {
+    fn new(init: &Init) -> Self {
+        Raft::new(
+            &init.node_id,
+            &init.node_ids,
+            Store::default(),
+            raft::Config::default(),
+        )
+    }
+}
+
+impl Replicated for Paxos<Store> {
+    fn new(init: &Init) -> Self {
+        Paxos::new(
+            &init.node_id,
+            &init.node_ids,
+            Store::default(),
+            paxos::Config::default(),
+        )
+    }
+}
+
+struct LinKvNode<C> {
+    node: String,
+    id: usize,
+    consensus: C,
+}
+
+impl<C: Replicated> LinKvNode<C> {
+    fn send_to(
+        &mut self,
+        dest: String,
+        payload: Payload<C::Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let message = Message {
+            src: self.node.clone(),
+            dest,
+            body: Body {
+                payload,
+                id: Some(self.id),
+                in_reply_to: None,
+            },
+        };
+        self.send(&message, output)?;
+        self.id += 1;
+        Ok(())
+    }
+
+    fn send_consensus(
+        &mut self,
+        out: Vec<(String, C::Payload)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for (dest, payload) in out {
+            self.send_to(
+                dest,
+                Payload::Internal(Internal::Consensus(payload)),
+                output,
+            )?;
+        }
+        self.reply_applied(output)
+    }
+
+    /// Answer clients whose requests reached this node and have now been
+    /// applied.
+    fn reply_applied(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        for (proposal, result) in self.consensus.take_applied() {
+            if proposal.origin != self.node {
+                continue;
+            }
+            let reply = Message {
+                src: self.node.clone(),
+                dest: proposal.client,
+                body: Body {
+                    id: Some(self.id),
+                    in_reply_to: proposal.msg_id,
+                    payload: Payload::Kv(result),
+                },
+            };
+            self.send(&reply, output)?;
+            self.id += 1;
+        }
+        Ok(())
+    }
+
+    /// Propose locally, or pass the request on to the leader. `from` is the
+    /// node that forwarded it to us, so it does not bounce straight back, and
+    /// `hops` how often it was forwarded already.
+    fn propose(
+        &mut self,
+        proposal: Proposal<kv::Payload>,
+        from: Option<&str>,
+        hops: usize,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        match self.consensus.propose(proposal.clone()) {
+            Ok(out) => self.send_consensus(out, output),
+            Err(Some(leader))
+                if leader != self.node && Some(leader.as_str()) != from && hops < MAX_HOPS =>
+            {
+                let hops = hops + 1;
+                self.send_to(
+                    leader,
+                    Payload::Internal(Internal::Forward { proposal, hops }),
+                    output,
+                )
+            }
+            Err(_) => self.unavailable(proposal, output),
+        }
+    }
+
+    /// Fail a request we cannot place. Only the origin answers its client,
+    /// so anyone else sends it back there with its hops used up.
+    fn unavailable(
+        &mut self,
+        proposal: Proposal<kv::Payload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        match proposal.origin.clone() {
+            origin if origin == self.node => {
+                let reply = Message {
+                    src: self.node.clone(),
+                    dest: proposal.client,
+                    body: Body {
+                        id: Some(self.id),
+                        in_reply_to: proposal.msg_id,
+                        payload: Payload::Kv(kv::Payload::Error {
+                            code: kv::TEMPORARILY_UNAVAILABLE,
+                            text: "no leader".to_string(),
+                        }),
+                    },
+                };
+                self.send(&reply, output)?;
+                self.id += 1;
+                Ok(())
+            }
+            origin => {
+                let hops = MAX_HOPS;
+                self.send_to(
+                    origin,
+                    Payload::Internal(Internal::Forward { proposal, hops }),
+                    output,
+                )
+            }
+        }
+    }
+}
+
+impl<C: Replicated> Node<Payload<C::Payload>, InjectedPayload> for LinKvNode<C> {
+    fn step(
+        &mut self,
+        event: Event<Payload<C::Payload>, InjectedPayload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(input) => match input.body.payload {
+                Payload::Kv(
+                    command @ (kv::Payload::Read { .. }
+                    | kv::Payload::Write { .. }
+                    | kv::Payload::Cas { .. }
+                    | kv::Payload::Txn { .. }
+                    | kv::Payload::Append { .. }
+                    | kv::Payload::ReadLog { .. }),
+                ) => {
+                    let proposal = Proposal {
+                        origin: self.node.clone(),
+                        client: input.src,
+                        msg_id: input.body.id,
+                        command,
+                    };
+                    self.propose(proposal, None, 0, output)?;
+                }
+                Payload::Kv(_) => {}
+                Payload::Internal(Internal::Forward { proposal, hops }) => {
+                    self.propose(proposal, Some(&input.src), hops, output)?;
+                }
+                Payload::Internal(Internal::Consensus(payload)) => {
+                    let out = self.consensus.handle(&input.src, payload);
+                    self.send_consensus(out, output)?;
+                }
+            },
+            Event::InjectedPayload(InjectedPayload::Tick) => {
+                let out = self.consensus.tick();
+                self.send_consensus(out, output)?;
+            }
+            Event::EOF => {}
+        }
+        Ok(())
+    }
+
+    fn from_init(
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload<C::Payload>, InjectedPayload>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        std::thread::spawn(move || {
+            // TODO: Handle EOF
+            loop {
+                std::thread::sleep(Duration::from_millis(50));
+                if tx
+                    .send(Event::InjectedPayload(InjectedPayload::Tick))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Ok(LinKvNode {
+            consensus: C::new(&init),
+            node: init.node_id,
+            id: 1,
+        })
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    match std::env::var(CONSENSUS_ENV).as_deref() {
+        Ok("paxos") => main_loop::<LinKvNode<Paxos<Store>>, _, _>(),
+        _ => main_loop::<LinKvNode<Raft<Store>>, _, _>(),
+    }
+}
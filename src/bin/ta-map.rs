@@ -1,37 +1,376 @@
-use rustengan::*;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, io::StdoutLock, str::FromStr, time::Duration};
+use anyhow::bail;
+use rustengan::{
+    hlc::{Clock, Timestamp, Version},
+    kv,
+    mvcc::{self, Versioned},
+    snapshot::{self, Transfers},
+    storage::{self, Storage},
+    *,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{Debug, Display},
+    hash::Hash,
+    io::StdoutLock,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum Payload {
-    Txn { txn: Vec<Op> },
-    TxnOk { txn: Vec<Op> },
-    Gossip { txn: Txn },
-    GossipOk,
+enum Payload<K, V> {
+    Txn {
+        txn: Vec<Op<K, V>>,
+    },
+    TxnOk {
+        txn: Vec<Op<K, V>>,
+    },
+    /// `progress` as in `GossipOk`.
+    Gossip {
+        txns: Vec<Txn<K, V>>,
+        #[serde(default)]
+        progress: HashMap<String, Progress>,
+    },
+    /// What the sender knows of every node's progress, its own up to date.
+    GossipOk {
+        progress: HashMap<String, Progress>,
+    },
+    /// The whole map, versioned by what its sender had applied.
+    Snapshot(snapshot::Payload<HashMap<String, usize>>),
+    Error {
+        code: isize,
+        text: String,
+    },
+    // lin-kv, for serializable transactions.
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: serde_json::Value,
+    },
+    Cas {
+        key: String,
+        from: serde_json::Value,
+        to: serde_json::Value,
+        #[serde(default, rename = "create_if_not_exists")]
+        put: bool,
+    },
+    CasOk,
 }
 
 enum InjectedPayload {
     Gossip,
 }
 
+const ISOLATION_ENV: &str = "RUSTENGAN_ISOLATION";
+/// In serializable mode, lin-kv key `ta-map/<n>` holds the n-th entry of
+/// the commit log.
+const COMMIT_PREFIX: &str = "ta-map/";
+/// Serializable transactions still running after this long fail.
+const TXN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Isolation {
+    /// Commit locally and replicate in the background; always available.
+    ReadCommitted,
+    /// Commit by claiming the next entry of a commit log in lin-kv;
+    /// transactions whose reads were overwritten in between abort.
+    Serializable,
+}
+
+impl FromStr for Isolation {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-committed" => Ok(Isolation::ReadCommitted),
+            "serializable" => Ok(Isolation::Serializable),
+            _ => Err(anyhow::anyhow!("unknown isolation level {s}")),
+        }
+    }
+}
+
+/// What the map can be keyed by.
+trait Key:
+    Clone + Debug + Display + Ord + Hash + Serialize + DeserializeOwned + Send + 'static
+{
+}
+
+impl<K> Key for K where
+    K: Clone + Debug + Display + Ord + Hash + Serialize + DeserializeOwned + Send + 'static
+{
+}
+
+/// Integer keys, as the txn workloads use, or string keys, as services do.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+enum AnyKey {
+    Int(i64),
+    Str(String),
+}
+
+impl Display for AnyKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnyKey::Int(key) => write!(f, "{key}"),
+            AnyKey::Str(key) => write!(f, "{key:?}"),
+        }
+    }
+}
+
+/// What a key can hold. Registers are plain values; `append` needs a value
+/// type with lists, as in the `txn-list-append` workload.
+trait Value: Clone + Debug + Serialize + DeserializeOwned + Send + 'static {
+    /// `current` with `element` appended, or `None` if it is not a list.
+    fn append(current: Option<Self>, element: Self) -> Option<Self>;
+}
+
+/// Any JSON, so integer registers and lists of integers alike: both txn
+/// workloads on the same node.
+impl Value for serde_json::Value {
+    fn append(current: Option<Self>, element: Self) -> Option<Self> {
+        match current {
+            None => Some(serde_json::Value::Array(vec![element])),
+            Some(serde_json::Value::Array(mut list)) => {
+                list.push(element);
+                Some(serde_json::Value::Array(list))
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// An entry of the serializable commit log: the value every key written by
+/// one transaction ends up with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "K: Key, V: Value")]
+struct Commit<K, V> {
+    #[serde(with = "pairs")]
+    writes: BTreeMap<K, V>,
+}
+
+/// A map as a list of `[key, value]` pairs, since JSON object keys can only
+/// be strings.
+mod pairs {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<K, V, S>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let pairs: Vec<(K, V)> = Deserialize::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+/// A serializable transaction waiting on lin-kv.
+#[derive(Debug)]
+struct InProgress<K, V> {
+    client: String,
+    msg_id: Option<usize>,
+    ops: Vec<Op<K, V>>,
+    /// Set once it has run, until it has an entry in the commit log.
+    claim: Option<Claim<K, V>>,
+    stage: Stage,
+    /// Failed if still running by then.
+    deadline: Instant,
+}
+
+#[derive(Debug)]
+enum Stage {
+    /// Catching up with the commit log, reading entry `slot`.
+    Reading { slot: usize },
+    /// Creating entry `slot` for its writes.
+    Committing { slot: usize },
+}
+
+/// What a serializable transaction needs to commit.
+#[derive(Debug)]
+struct Claim<K, V> {
+    /// The ops with reads filled in, to reply with.
+    results: Vec<Op<K, V>>,
+    commit: Commit<K, V>,
+    /// The commit log entry each key it read was last written by.
+    reads: BTreeMap<K, usize>,
+}
+
+/// A transaction that ran to the end.
+struct Executed<K, V> {
+    /// The ops with reads filled in, to reply with.
+    results: Vec<Op<K, V>>,
+    /// Writes and appends, in order.
+    writes: Vec<Op<K, V>>,
+    /// The value every written key ends up with.
+    values: BTreeMap<K, V>,
+}
+
+/// Run `ops` with writes buffered: reads see the transaction's own earlier
+/// writes and otherwise whatever `read` returns, but nothing is installed
+/// until it has run to the end.
+fn execute<K: Key, V: Value>(
+    ops: &[Op<K, V>],
+    read: impl Fn(&K) -> Option<V>,
+) -> anyhow::Result<Executed<K, V>> {
+    let mut values = BTreeMap::new();
+    let mut results = Vec::with_capacity(ops.len());
+    let mut writes = Vec::new();
+    for op in ops {
+        let current = |values: &BTreeMap<K, V>| match values.get(&op.key) {
+            Some(val) => Some(val.clone()),
+            None => read(&op.key),
+        };
+        match op.op_type {
+            OpType::Read => {
+                let val = current(&values);
+                results.push(Op { val, ..op.clone() });
+                continue;
+            }
+            OpType::Write => {
+                let Some(val) = op.val.clone() else {
+                    bail!("write to {} without a value", op.key);
+                };
+                values.insert(op.key.clone(), val);
+            }
+            OpType::Append => {
+                let Some(element) = op.val.clone() else {
+                    bail!("append to {} without a value", op.key);
+                };
+                let Some(appended) = V::append(current(&values), element) else {
+                    bail!("{} does not hold a list", op.key);
+                };
+                values.insert(op.key.clone(), appended);
+            }
+        }
+        writes.push(op.clone());
+        results.push(op.clone());
+    }
+    Ok(Executed {
+        results,
+        writes,
+        values,
+    })
+}
+
+/// Most transactions sent to a peer in one gossip message.
+const GOSSIP_BATCH: usize = 64;
+/// Other origins' transactions are relayed every this many gossip rounds;
+/// our own go out every round.
+const RELAY_EVERY: usize = 5;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum OpType {
     Read,
     Write,
+    Append,
 }
 
 #[derive(Debug, Clone)]
-struct Op {
+struct Op<K, V> {
     op_type: OpType,
-    key: isize,
-    val: Option<isize>,
+    key: K,
+    val: Option<V>,
+}
+
+/// What one transaction did to a key, stored as that key's version.
+/// Appends stay appends, so concurrent ones from different nodes all end
+/// up in the list, in version order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Change<V> {
+    Set(V),
+    Append(Vec<V>),
+}
+
+impl<V: Value> Change<V> {
+    /// Follow this change with `op` from the same transaction.
+    fn then<K>(self, op: &Op<K, V>) -> Self {
+        let Some(val) = op.val.clone() else {
+            return self;
+        };
+        match (self, &op.op_type) {
+            (_, OpType::Write) => Change::Set(val),
+            (Change::Set(current), OpType::Append) => {
+                Change::Set(V::append(Some(current.clone()), val).unwrap_or(current))
+            }
+            (Change::Append(mut elements), OpType::Append) => {
+                elements.push(val);
+                Change::Append(elements)
+            }
+            (change, OpType::Read) => change,
+        }
+    }
+
+    fn apply(&self, current: Option<V>) -> Option<V> {
+        match self {
+            Change::Set(val) => Some(val.clone()),
+            Change::Append(elements) => elements.iter().fold(current, |current, element| {
+                V::append(current.clone(), element.clone()).or(current)
+            }),
+        }
+    }
 }
 
+impl<V> Versioned for Change<V> {
+    fn is_whole(&self) -> bool {
+        matches!(self, Change::Set(_))
+    }
+}
+
+/// A committed transaction's write set, tagged with the incarnation of the
+/// node it committed on and its place in that incarnation's sequence. All
+/// its writes carry the version `(time, origin)`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Txn {
-    ops: Vec<Op>,
+struct Txn<K, V> {
+    origin: String,
+    seq: usize,
+    time: Timestamp,
+    ops: Vec<Op<K, V>>,
+}
+
+impl<K, V> Txn<K, V> {
+    fn version(&self) -> Version {
+        Version {
+            time: self.time,
+            node: self.origin.clone(),
+        }
+    }
+}
+
+/// How far a node has got, as it reported at some point.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Progress {
+    /// Highest sequence number applied from each origin.
+    applied: HashMap<String, usize>,
+    /// When it reported. Everything it commits afterwards is newer, so a
+    /// newer report replaces an older one.
+    frontier: Timestamp,
+}
+
+/// Every version in a map, as snapshots carry it.
+type Versions<K, V> = Vec<(K, Version, Change<V>)>;
+
+/// What is written to storage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "K: Key, V: Value")]
+enum Record<K, V> {
+    Txn(Txn<K, V>),
+    /// A peer's snapshot, installed in place of the transactions it covers.
+    Snapshot {
+        applied: HashMap<String, usize>,
+        versions: Versions<K, V>,
+    },
 }
 
 impl FromStr for OpType {
@@ -40,11 +379,12 @@ impl FromStr for OpType {
         match s {
             "r" => Ok(OpType::Read),
             "w" => Ok(OpType::Write),
+            "append" => Ok(OpType::Append),
             _ => Err(anyhow::anyhow!("Invalid op type")),
         }
     }
 }
-impl Serialize for Op {
+impl<K: Serialize, V: Serialize> Serialize for Op<K, V> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -52,118 +392,674 @@ impl Serialize for Op {
         let op = match &self.op_type {
             OpType::Read => "r".to_string(),
             OpType::Write => "w".to_string(),
+            OpType::Append => "append".to_string(),
         };
         (op, &self.key, &self.val).serialize(serializer)
     }
 }
-impl<'de> Deserialize<'de> for Op {
+impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for Op<K, V> {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        Deserialize::deserialize(deserializer).map(
-            |(ot, key, val): (String, isize, Option<isize>)| {
-                let op_type = OpType::from_str(ot.as_ref()).unwrap();
-                Op { op_type, key, val }
-            },
-        )
+        let (ot, key, val): (String, K, Option<V>) = Deserialize::deserialize(deserializer)?;
+        let op_type = OpType::from_str(ot.as_ref()).map_err(serde::de::Error::custom)?;
+        Ok(Op { op_type, key, val })
     }
 }
-struct TAMap {
+struct TAMap<K, V> {
     id: usize,
     node: String,
+    /// Origin id of this incarnation: the node id and when it started. A
+    /// node restarted without storage starts a new sequence under a new
+    /// origin, rather than reusing numbers peers already have.
+    origin: String,
     nodes: Vec<String>,
-    map: HashMap<String, isize>,
-    txns_to_gossip: HashMap<String, Vec<Txn>>,
-    gossiped_txn: HashMap<usize, (String, Vec<Txn>)>,
+    /// Every key's changes by the version of the transaction that made
+    /// them. The latest value folds them in version order, so replicas
+    /// agree on it whatever order they apply transactions in.
+    map: mvcc::Store<K, Change<V>, Version>,
+    clock: Clock,
+    /// Highest sequence number applied from each origin, ourselves
+    /// included. Everything before it was applied too, in order.
+    applied: HashMap<String, usize>,
+    /// Transactions that arrived ahead of one they follow.
+    pending: HashMap<String, BTreeMap<usize, Txn<K, V>>>,
+    /// Applied transactions, by origin and sequence number, that some peer
+    /// has not acknowledged yet. They are resent until it does, whichever
+    /// node they came from, so a transaction reaches every peer even if its
+    /// origin dies after reaching just one.
+    log: HashMap<String, BTreeMap<usize, Txn<K, V>>>,
+    /// Each peer's newest report we have seen, from the peer itself or
+    /// passed on by another. A peer that restarted without storage reports
+    /// having lost what it had, and gets it again.
+    progress: HashMap<String, Progress>,
+    /// Highest sequence number from each origin dropped from `log`, or
+    /// covered by a snapshot we installed. A peer that has not applied
+    /// that far can only catch up from a snapshot.
+    pruned: HashMap<String, usize>,
+    snapshots: Transfers<HashMap<String, usize>, Versions<K, V>>,
+    ticks: usize,
+    /// Every applied transaction and installed snapshot, to rebuild `map`,
+    /// `applied` and `log` from.
+    storage: Box<dyn Storage<Record<K, V>>>,
+    isolation: Isolation,
+    /// Serializable transactions by the id of their outstanding lin-kv
+    /// request.
+    in_progress: HashMap<usize, InProgress<K, V>>,
+    /// The map as of commit log entry `commits`, with the entry that last
+    /// wrote each key.
+    committed: BTreeMap<K, (usize, V)>,
+    commits: usize,
 }
 
-impl Node<Payload, InjectedPayload> for TAMap {
+impl<K: Key, V: Value> TAMap<K, V> {
+    fn send_to(
+        &mut self,
+        dest: &str,
+        payload: Payload<K, V>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<usize> {
+        let id = self.id;
+        let message = Message {
+            src: self.node.clone(),
+            dest: dest.to_string(),
+            body: Body {
+                payload,
+                id: Some(id),
+                in_reply_to: None,
+            },
+        };
+        self.send(&message, output)?;
+        self.id += 1;
+        Ok(id)
+    }
+
+    fn reply_to(
+        &mut self,
+        client: String,
+        msg_id: Option<usize>,
+        payload: Payload<K, V>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let message = Message {
+            src: self.node.clone(),
+            dest: client,
+            body: Body {
+                payload,
+                id: Some(self.id),
+                in_reply_to: msg_id,
+            },
+        };
+        self.send(&message, output)?;
+        self.id += 1;
+        Ok(())
+    }
+
+    /// The value of `key` as of `snapshot`.
+    fn read_at(&self, key: &K, snapshot: &Version) -> Option<V> {
+        self.map
+            .read(key, snapshot)
+            .fold(None, |current, change| change.apply(current))
+    }
+
+    /// Read the commit log entry after the last one we have.
+    fn catch_up(&mut self, txn: InProgress<K, V>, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let slot = self.commits + 1;
+        let payload = Payload::Read {
+            key: format!("{COMMIT_PREFIX}{slot}"),
+        };
+        let id = self.send_to(kv::LIN_KV, payload, output)?;
+        let stage = Stage::Reading { slot };
+        self.in_progress.insert(id, InProgress { stage, ..txn });
+        Ok(())
+    }
+
+    fn apply_commit(&mut self, slot: usize, commit: Commit<K, V>) {
+        if slot != self.commits + 1 {
+            return;
+        }
+        for (key, val) in commit.writes {
+            self.committed.insert(key, (slot, val));
+        }
+        self.commits = slot;
+    }
+
+    /// Optimistic concurrency control, once caught up with the commit log:
+    /// run `txn` against the map it adds up to, then create the next entry
+    /// with its writes. If another transaction creates it first, `txn`
+    /// catches up and comes back here, and aborts only if that wrote a key
+    /// it read. Read-only transactions need no entry: the read that found
+    /// the end of the log was linearizable.
+    fn claim(&mut self, mut txn: InProgress<K, V>, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let version = |key: &K| self.committed.get(key).map_or(0, |(slot, _)| *slot);
+        let claim = match txn.claim.take() {
+            Some(claim) => {
+                if let Some((key, _)) = claim.reads.iter().find(|(k, v)| version(k) != **v) {
+                    let payload = Payload::Error {
+                        code: kv::TXN_CONFLICT,
+                        text: format!("{key} was written since it was read"),
+                    };
+                    return self.reply_to(txn.client, txn.msg_id, payload, output);
+                }
+                claim
+            }
+            None => {
+                let read = |key: &K| self.committed.get(key).map(|(_, val)| val.clone());
+                let executed = match execute(&txn.ops, read) {
+                    Ok(executed) => executed,
+                    Err(e) => {
+                        let payload = Payload::Error {
+                            code: kv::MALFORMED_REQUEST,
+                            text: e.to_string(),
+                        };
+                        return self.reply_to(txn.client, txn.msg_id, payload, output);
+                    }
+                };
+                if executed.writes.is_empty() {
+                    let payload = Payload::TxnOk {
+                        txn: executed.results,
+                    };
+                    return self.reply_to(txn.client, txn.msg_id, payload, output);
+                }
+                // Appends read the list they extend; plain writes read nothing.
+                let reads = txn
+                    .ops
+                    .iter()
+                    .filter(|op| matches!(op.op_type, OpType::Read | OpType::Append))
+                    .map(|op| (op.key.clone(), version(&op.key)))
+                    .collect();
+                Claim {
+                    results: executed.results,
+                    commit: Commit {
+                        writes: executed.values,
+                    },
+                    reads,
+                }
+            }
+        };
+        let slot = self.commits + 1;
+        // Entries are created once and never change, so `from` only
+        // matters when the entry exists, and then it never matches.
+        let payload = Payload::Cas {
+            key: format!("{COMMIT_PREFIX}{slot}"),
+            from: serde_json::Value::Null,
+            to: serde_json::to_value(&claim.commit)?,
+            put: true,
+        };
+        let id = self.send_to(kv::LIN_KV, payload, output)?;
+        let txn = InProgress {
+            claim: Some(claim),
+            stage: Stage::Committing { slot },
+            ..txn
+        };
+        self.in_progress.insert(id, txn);
+        Ok(())
+    }
+
+    /// Fail serializable transactions that ran out of time.
+    fn expire(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .in_progress
+            .iter()
+            .filter(|(_, txn)| txn.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            let Some(txn) = self.in_progress.remove(&id) else {
+                continue;
+            };
+            let payload = match txn.stage {
+                Stage::Reading { .. } => Payload::Error {
+                    code: kv::TEMPORARILY_UNAVAILABLE,
+                    text: "timed out catching up with the commit log".to_string(),
+                },
+                // The entry may have been created after all, so this is
+                // not a definite failure.
+                Stage::Committing { .. } => Payload::Error {
+                    code: kv::TIMEOUT,
+                    text: "timed out committing".to_string(),
+                },
+            };
+            self.reply_to(txn.client, txn.msg_id, payload, output)?;
+        }
+        Ok(())
+    }
+
+    /// Persist and apply the next transaction in its origin's sequence.
+    fn commit(&mut self, txn: Txn<K, V>) -> anyhow::Result<()> {
+        self.storage.append(&Record::Txn(txn.clone()))?;
+        self.install(txn);
+        Ok(())
+    }
+
+    fn install(&mut self, txn: Txn<K, V>) {
+        self.clock.observe(txn.time);
+        self.apply_writes(&txn);
+        self.applied.insert(txn.origin.clone(), txn.seq);
+        self.log
+            .entry(txn.origin.clone())
+            .or_default()
+            .insert(txn.seq, txn);
+    }
+
+    /// Apply a peer's transaction once everything before it from the same
+    /// origin is applied. Ones we already have are ignored.
+    fn receive(&mut self, txn: Txn<K, V>) -> anyhow::Result<()> {
+        let applied = self.applied.get(&txn.origin).copied().unwrap_or_default();
+        if txn.seq <= applied {
+            return Ok(());
+        }
+        let origin = txn.origin.clone();
+        self.pending
+            .entry(origin.clone())
+            .or_default()
+            .insert(txn.seq, txn);
+        self.apply_pending(&origin)
+    }
+
+    /// Apply `origin`'s held-back transactions that are next in sequence.
+    fn apply_pending(&mut self, origin: &str) -> anyhow::Result<()> {
+        loop {
+            let next = self.applied.get(origin).copied().unwrap_or_default() + 1;
+            let Some(txn) = self.pending.get_mut(origin).and_then(|p| p.remove(&next)) else {
+                break;
+            };
+            self.commit(txn)?;
+        }
+        Ok(())
+    }
+
+    /// Take in every version of a peer's map and everything it had applied.
+    /// Versions merge like transactions do, so whatever we had beyond the
+    /// snapshot survives.
+    fn install_snapshot(&mut self, applied: &HashMap<String, usize>, versions: &Versions<K, V>) {
+        for (key, version, change) in versions {
+            self.clock.observe(version.time);
+            self.map.write(key.clone(), version.clone(), change.clone());
+        }
+        for (origin, &seq) in applied {
+            let current = self.applied.entry(origin.clone()).or_default();
+            if *current >= seq {
+                continue;
+            }
+            *current = seq;
+            let pruned = self.pruned.entry(origin.clone()).or_default();
+            *pruned = (*pruned).max(seq);
+            if let Some(txns) = self.log.get_mut(origin) {
+                txns.retain(|s, _| *s > seq);
+            }
+            if let Some(txns) = self.pending.get_mut(origin) {
+                txns.retain(|s, _| *s > seq);
+            }
+        }
+    }
+
+    /// Stream `peer` our map if it is missing transactions we no longer
+    /// hold. True while a snapshot is on its way there, in place of gossip.
+    fn snapshot_if_behind(&mut self, peer: &str, output: &mut StdoutLock) -> anyhow::Result<bool> {
+        if self.snapshots.sending(peer).is_some() {
+            return Ok(true);
+        }
+        if !self.progress.contains_key(peer) {
+            return Ok(false);
+        }
+        let behind = self
+            .pruned
+            .iter()
+            .any(|(origin, pruned)| self.acked(peer, origin) < *pruned);
+        if !behind {
+            return Ok(false);
+        }
+        let versions: Versions<K, V> = self
+            .map
+            .iter()
+            .map(|(key, version, change)| (key.clone(), version.clone(), change.clone()))
+            .collect();
+        let out = self.snapshots.send(peer, self.applied.clone(), &versions)?;
+        self.snapshot_out(out, output)?;
+        Ok(true)
+    }
+
+    /// Highest sequence number from `origin` that `peer` reported applying.
+    fn acked(&self, peer: &str, origin: &str) -> usize {
+        self.progress
+            .get(peer)
+            .and_then(|progress| progress.applied.get(origin))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Every node's progress we know of, with our own as of now.
+    fn report(&mut self) -> HashMap<String, Progress> {
+        let mut progress = self.progress.clone();
+        let ours = Progress {
+            applied: self.applied.clone(),
+            frontier: self.clock.now(),
+        };
+        progress.insert(self.node.clone(), ours);
+        progress
+    }
+
+    /// Take in a peer's reports, and stop resending what every peer has.
+    fn learn(&mut self, reports: &HashMap<String, Progress>) {
+        for (node, report) in reports {
+            if *node == self.node {
+                continue;
+            }
+            let newer = self
+                .progress
+                .get(node)
+                .is_none_or(|known| known.frontier < report.frontier);
+            if newer {
+                self.progress.insert(node.clone(), report.clone());
+            }
+        }
+        if self.nodes.is_empty() {
+            return;
+        }
+        for origin in self.log.keys().cloned().collect::<Vec<_>>() {
+            let everywhere = self
+                .nodes
+                .iter()
+                .map(|n| self.acked(n, &origin))
+                .min()
+                .unwrap_or_default();
+            if let Some(txns) = self.log.get_mut(&origin) {
+                txns.retain(|seq, _| *seq > everywhere);
+            }
+            let applied = self.applied.get(&origin).copied().unwrap_or_default();
+            let pruned = self.pruned.entry(origin).or_default();
+            *pruned = (*pruned).max(everywhere.min(applied));
+        }
+        self.log.retain(|_, txns| !txns.is_empty());
+    }
+
+    /// A time below which every node has applied every transaction there
+    /// will ever be, so versions below it can no longer be joined by older
+    /// ones. Each node's frontier bounds what it commits from then on, and
+    /// the first transaction from each origin that not every node has yet
+    /// bounds the ones already committed. `None` until every peer has
+    /// reported, or while we lack a transaction some node has.
+    fn stable(&mut self) -> Option<Timestamp> {
+        let mut horizon = self.clock.now();
+        let mut reports = vec![&self.applied];
+        for node in &self.nodes {
+            let progress = self.progress.get(node)?;
+            horizon = horizon.min(progress.frontier);
+            reports.push(&progress.applied);
+        }
+        let origins: BTreeSet<&String> =
+            reports.iter().flat_map(|applied| applied.keys()).collect();
+        for origin in origins {
+            let seqs = reports
+                .iter()
+                .map(|applied| applied.get(origin).copied().unwrap_or_default());
+            let everywhere = seqs.clone().min().unwrap_or_default();
+            if seqs.max().unwrap_or_default() > everywhere {
+                let first = self.log.get(origin)?.get(&(everywhere + 1))?;
+                horizon = horizon.min(first.time);
+            }
+        }
+        Some(horizon)
+    }
+
+    fn snapshot_out(
+        &mut self,
+        out: Vec<(String, snapshot::Payload<HashMap<String, usize>>)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for (dest, payload) in out {
+            self.send_to(&dest, Payload::Snapshot(payload), output)?;
+        }
+        Ok(())
+    }
+
+    /// Install a write set. Everything happens within one step, so readers
+    /// see all of a transaction's writes or none of them.
+    fn apply_writes(&mut self, txn: &Txn<K, V>) {
+        let mut changes: BTreeMap<K, Change<V>> = BTreeMap::new();
+        for op in &txn.ops {
+            let change = match (changes.remove(&op.key), op.val.clone()) {
+                (Some(change), _) => change.then(op),
+                (None, Some(val)) => match op.op_type {
+                    OpType::Write => Change::Set(val),
+                    OpType::Append => Change::Append(vec![val]),
+                    OpType::Read => continue,
+                },
+                (None, None) => continue,
+            };
+            changes.insert(op.key.clone(), change);
+        }
+        let version = txn.version();
+        for (key, change) in changes {
+            self.map.write(key, version.clone(), change);
+        }
+    }
+}
+
+impl<K: Key, V: Value> Node<Payload<K, V>, InjectedPayload> for TAMap<K, V> {
     fn step(
         &mut self,
-        event: rustengan::Event<Payload, InjectedPayload>,
+        event: rustengan::Event<Payload<K, V>, InjectedPayload>,
         output: &mut StdoutLock,
     ) -> anyhow::Result<()> {
         match &event {
             Event::Message(input) => match &input.body.payload {
+                Payload::Txn { txn } if self.isolation == Isolation::Serializable => {
+                    let txn = InProgress {
+                        client: input.src.clone(),
+                        msg_id: input.body.id,
+                        ops: txn.clone(),
+                        claim: None,
+                        stage: Stage::Reading { slot: 0 },
+                        deadline: Instant::now() + TXN_TIMEOUT,
+                    };
+                    self.catch_up(txn, output)?;
+                }
                 Payload::Txn { txn } => {
-                    let mut response_txn: Vec<Op> = Vec::new();
-                    for op in txn {
-                        match op.op_type {
-                            OpType::Read => {
-                                let val = self.map.get(&op.key.to_string()).cloned();
-                                response_txn.push(Op {
-                                    op_type: op.op_type.clone(),
-                                    key: op.key.clone(),
-                                    val,
-                                });
+                    let snapshot = self.map.begin(Version {
+                        time: self.clock.now(),
+                        node: self.node.clone(),
+                    });
+                    let executed = execute(txn, |key| self.read_at(key, &snapshot));
+                    let written: Vec<K> = match &executed {
+                        Ok(executed) => executed.values.keys().cloned().collect(),
+                        Err(_) => Vec::new(),
+                    };
+                    let conflict = self.map.conflict(&snapshot, &written).cloned();
+                    self.map.end(&snapshot);
+                    let payload = match (executed, conflict) {
+                        (Ok(_), Some(key)) => Payload::Error {
+                            code: kv::TXN_CONFLICT,
+                            text: format!("{key} was written since the snapshot"),
+                        },
+                        (Ok(executed), None) => {
+                            if !executed.writes.is_empty() {
+                                let seq =
+                                    self.applied.get(&self.origin).copied().unwrap_or_default();
+                                let time = self.clock.now();
+                                self.commit(Txn {
+                                    origin: self.origin.clone(),
+                                    seq: seq + 1,
+                                    time,
+                                    ops: executed.writes,
+                                })?;
                             }
-                            OpType::Write => {
-                                if let Some(val) = op.val {
-                                    self.map.insert(op.key.to_string(), val);
-                                    response_txn.push(op.clone());
-                                }
+                            Payload::TxnOk {
+                                txn: executed.results,
                             }
                         }
-                    }
-                    for node in &self.nodes {
-                        if &self.node != node {
-                            self.txns_to_gossip
-                                .entry(node.clone())
-                                .or_insert_with(Vec::new)
-                                .push(Txn { ops: txn.clone() });
-                        }
-                    }
-
-                    let reply = input
-                        .construct_reply(Payload::TxnOk { txn: response_txn }, Some(&mut self.id));
+                        // Aborted before anything was installed.
+                        (Err(e), _) => Payload::Error {
+                            code: kv::MALFORMED_REQUEST,
+                            text: e.to_string(),
+                        },
+                    };
+                    let reply = input.construct_reply(payload, Some(&mut self.id));
                     self.send(&reply, output)?;
                 }
-                Payload::TxnOk { txn: _txn } => {}
-                Payload::Gossip { txn } => {
-                    for op in &txn.ops {
-                        match op.op_type {
-                            OpType::Read => {}
-                            OpType::Write => {
-                                if let Some(val) = op.val {
-                                    self.map.insert(op.key.to_string(), val);
+                Payload::ReadOk { value } => {
+                    let pending = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.in_progress.remove(&id));
+                    if let Some(txn) = pending {
+                        if let Stage::Reading { slot } = txn.stage {
+                            match serde_json::from_value(value.clone()) {
+                                Ok(commit) => self.apply_commit(slot, commit),
+                                // Nothing after this entry can be applied
+                                // either, so the transaction cannot run.
+                                Err(e) => {
+                                    let payload = Payload::Error {
+                                        code: kv::CRASH,
+                                        text: format!("commit log entry {slot} is corrupt: {e}"),
+                                    };
+                                    return self.reply_to(txn.client, txn.msg_id, payload, output);
                                 }
                             }
                         }
+                        self.catch_up(txn, output)?;
                     }
-                    let reply = input.construct_reply(Payload::GossipOk, Some(&mut self.id));
+                }
+                Payload::CasOk => {
+                    let pending = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.in_progress.remove(&id));
+                    if let Some(InProgress {
+                        client,
+                        msg_id,
+                        claim: Some(claim),
+                        stage: Stage::Committing { slot },
+                        ..
+                    }) = pending
+                    {
+                        self.apply_commit(slot, claim.commit);
+                        let payload = Payload::TxnOk { txn: claim.results };
+                        self.reply_to(client, msg_id, payload, output)?;
+                    }
+                }
+                Payload::Error { code, text } => {
+                    let pending = input
+                        .body
+                        .in_reply_to
+                        .and_then(|id| self.in_progress.remove(&id));
+                    let Some(txn) = pending else {
+                        return Ok(());
+                    };
+                    match (&txn.stage, *code) {
+                        // The end of the log.
+                        (Stage::Reading { .. }, kv::KEY_DOES_NOT_EXIST) => {
+                            return self.claim(txn, output);
+                        }
+                        // Another transaction took the entry.
+                        (Stage::Committing { .. }, kv::PRECONDITION_FAILED) => {
+                            return self.catch_up(txn, output);
+                        }
+                        _ => {}
+                    }
+                    let payload = Payload::Error {
+                        code: *code,
+                        text: text.clone(),
+                    };
+                    self.reply_to(txn.client, txn.msg_id, payload, output)?;
+                }
+                Payload::TxnOk { .. } | Payload::Read { .. } | Payload::Cas { .. } => {}
+                Payload::Gossip { txns, progress } => {
+                    self.learn(progress);
+                    for txn in txns {
+                        self.receive(txn.clone())?;
+                    }
+                    let payload = Payload::GossipOk {
+                        progress: self.report(),
+                    };
+                    let reply = input.construct_reply(payload, Some(&mut self.id));
                     self.send(&reply, output)?;
                 }
-                Payload::GossipOk => {
-                    let ref_msg_id = input.body.in_reply_to.unwrap();
-                    self.gossiped_txn.remove(&ref_msg_id);
+                Payload::GossipOk { progress } => self.learn(progress),
+                Payload::Snapshot(payload) => {
+                    let out = self.snapshots.handle(&input.src, payload);
+                    self.snapshot_out(out, output)?;
+                    for installed in self.snapshots.take_installed() {
+                        let record = Record::Snapshot {
+                            applied: installed.version,
+                            versions: installed.state,
+                        };
+                        self.storage.append(&record)?;
+                        if let Record::Snapshot { applied, versions } = &record {
+                            self.install_snapshot(applied, versions);
+                        }
+                        for origin in self.pending.keys().cloned().collect::<Vec<_>>() {
+                            self.apply_pending(&origin)?;
+                        }
+                        // Tell the sender right away, before it decides we
+                        // are still behind.
+                        let payload = Payload::GossipOk {
+                            progress: self.report(),
+                        };
+                        self.send_to(&installed.from, payload, output)?;
+                    }
                 }
             },
             Event::InjectedPayload(injected_input) => match injected_input {
                 InjectedPayload::Gossip => {
-                    if let Some(node) = self.txns_to_gossip.keys().next().cloned() {
-                        let txns = self.txns_to_gossip.get(&node).unwrap().clone();
-                        for t in &txns {
-                            let payload = Payload::Gossip { txn: t.clone() };
-                            let msg = Message {
-                                src: self.node.clone(),
-                                dest: node.clone(),
-                                body: Body {
-                                    payload,
-                                    id: Some(self.id),
-                                    in_reply_to: None,
-                                },
-                            };
-                            self.send(&msg, output)?;
-                            self.gossiped_txn
-                                .insert(self.id, (node.clone(), txns.clone()));
-                            self.id += 1;
+                    self.storage.tick()?;
+                    self.expire(output)?;
+                    if let Some(time) = self.stable() {
+                        let horizon = Version {
+                            time,
+                            node: String::new(),
+                        };
+                        self.map.collapse(&horizon, |changes| {
+                            let value = changes
+                                .iter()
+                                .fold(None, |current, change| change.apply(current));
+                            value.map(Change::Set)
+                        });
+                    }
+                    self.map.gc();
+                    let out = self.snapshots.tick();
+                    self.snapshot_out(out, output)?;
+                    self.ticks += 1;
+                    let relay = self.ticks.is_multiple_of(RELAY_EVERY);
+                    for node in self.nodes.clone() {
+                        if self.snapshot_if_behind(&node, output)? {
+                            continue;
                         }
-                        self.txns_to_gossip.remove(&node);
+                        let txns: Vec<Txn<K, V>> = self
+                            .log
+                            .iter()
+                            .filter(|(origin, _)| relay || **origin == self.origin)
+                            .flat_map(|(origin, txns)| txns.range(self.acked(&node, origin) + 1..))
+                            .take(GOSSIP_BATCH)
+                            .map(|(_, txn)| txn.clone())
+                            .collect();
+                        // Peers we have not heard from yet hear from us, so
+                        // they learn what we are missing after a restart.
+                        let announce = relay && !self.progress.contains_key(&node);
+                        if txns.is_empty() && !announce {
+                            continue;
+                        }
+                        let msg = Message {
+                            src: self.node.clone(),
+                            dest: node,
+                            body: Body {
+                                payload: Payload::Gossip {
+                                    txns,
+                                    progress: self.report(),
+                                },
+                                id: Some(self.id),
+                                in_reply_to: None,
+                            },
+                        };
+                        self.send(&msg, output)?;
+                        self.id += 1;
                     }
                 }
             },
-            Event::EOF => todo!(),
+            Event::EOF => self.storage.sync()?,
         }
 
         Ok(())
@@ -171,7 +1067,7 @@ impl Node<Payload, InjectedPayload> for TAMap {
 
     fn from_init(
         init: Init,
-        tx: std::sync::mpsc::Sender<rustengan::Event<Payload, InjectedPayload>>,
+        tx: std::sync::mpsc::Sender<rustengan::Event<Payload<K, V>, InjectedPayload>>,
     ) -> anyhow::Result<Self>
     where
         Self: Sized,
@@ -188,22 +1084,53 @@ impl Node<Payload, InjectedPayload> for TAMap {
                 }
             }
         });
-        let node = TAMap {
+        let isolation = match std::env::var(ISOLATION_ENV) {
+            Ok(isolation) => isolation.parse()?,
+            Err(_) => Isolation::ReadCommitted,
+        };
+        let storage = storage::open(&init.node_id, "ta-map")?;
+        let mut clock = Clock::default();
+        let origin = format!("{}@{}", init.node_id, clock.now().wall);
+        let snapshots = Transfers::new(&init.node_id, snapshot::Config::default());
+        let mut node = TAMap {
             id: 1,
+            origin,
             nodes: init
                 .node_ids
                 .into_iter()
                 .filter(|n| n != &init.node_id)
                 .collect(),
             node: init.node_id,
-            map: HashMap::new(),
-            txns_to_gossip: HashMap::new(),
-            gossiped_txn: HashMap::new(),
+            map: mvcc::Store::default(),
+            clock,
+            applied: HashMap::new(),
+            pending: HashMap::new(),
+            log: HashMap::new(),
+            progress: HashMap::new(),
+            pruned: HashMap::new(),
+            snapshots,
+            ticks: 0,
+            storage,
+            isolation,
+            in_progress: HashMap::new(),
+            committed: BTreeMap::new(),
+            commits: 0,
         };
+        // Peers' acknowledgements are not persisted, so everything replayed
+        // goes out again and they skip what they have. Our earlier
+        // incarnations' transactions are relayed like any other origin's.
+        for record in node.storage.replay()? {
+            match record {
+                Record::Txn(txn) => node.install(txn),
+                Record::Snapshot { applied, versions } => {
+                    node.install_snapshot(&applied, &versions)
+                }
+            }
+        }
         Ok(node)
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    main_loop::<TAMap, _, _>()
+    main_loop::<TAMap<AnyKey, serde_json::Value>, _, _>()
 }
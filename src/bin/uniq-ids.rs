@@ -24,7 +24,7 @@ impl Node<Payload> for UniqNode {
             panic!("");
         };
         match &input.body.payload {
-            Payload::Generate {} => {
+            Payload::Generate => {
                 let guid = format!("{}-{}", self.node, self.id);
                 let reply = input.construct_reply(Payload::GenerateOk { guid }, Some(&mut self.id));
                 self.send(&reply, output)?;
@@ -0,0 +1,776 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+    io::StdoutLock,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    digest::RangeSet,
+    failure_detector::{FailureDetector, Suspicion},
+    membership::{self, Change, HyParView},
+    retry::{Entry, InFlight},
+    rng::Rng,
+    snapshot::{self, Transfers},
+    topology::Strategy,
+    Body, Event, Init, Message, Node,
+};
+
+const MODE_ENV: &str = "RUSTENGAN_BROADCAST";
+/// How many non-tree peers get IHAVE announcements.
+const LAZY_FANOUT: usize = 3;
+/// How long to wait for an announced message before grafting the announcer.
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(400);
+/// Every this many gossip ticks, exchange full digests with the gossip peers.
+const DIGEST_EVERY: usize = 5;
+/// Resend a gossip message if its `GossipOk` has not arrived after this long.
+const GOSSIP_TIMEOUT: Duration = Duration::from_millis(600);
+const GOSSIP_ATTEMPTS: usize = 5;
+/// Upper bound on unacknowledged gossip messages kept around.
+const IN_FLIGHT_CAPACITY: usize = 1024;
+/// A peer whose digest shows it missing at least this many messages gets a
+/// snapshot instead of gossip.
+const SNAPSHOT_THRESHOLD: usize = 256;
+/// How long plumtree remembers who delivered a message first: as long as
+/// that peer may still be retransmitting it.
+const DELIVERED_WINDOW: Duration =
+    Duration::from_millis(GOSSIP_TIMEOUT.as_millis() as u64 * GOSSIP_ATTEMPTS as u64);
+
+/// A value the broadcast node can disseminate.
+///
+/// `id` must be stable: the same value has to map to the same id on every
+/// node, because peers only ever exchange ids in digests.
+pub trait Item: Clone + Hash + Eq + Serialize + DeserializeOwned + Send + 'static {
+    fn id(&self) -> usize;
+}
+
+impl Item for usize {
+    fn id(&self) -> usize {
+        *self
+    }
+}
+
+/// Strings are identified by their 64-bit FNV-1a hash, which is fixed by
+/// its definition, so every node and every build agrees on it. The ids are
+/// sparse, so digests stay roughly one range per string.
+///
+/// Two distinct strings with the same hash cannot both be disseminated:
+/// the node that sees the second one fails with an error rather than
+/// dropping it. Use an item type with unique ids, e.g. origin node plus
+/// counter, where that matters.
+impl Item for String {
+    fn id(&self) -> usize {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in self.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash as usize
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload<T> {
+    Read,
+    Broadcast {
+        message: T,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    ReadOk {
+        messages: Vec<T>,
+    },
+    BroadcastOk,
+    TopologyOk,
+    Gossip {
+        new_messages: Vec<T>,
+    },
+    GossipOk {
+        digest: RangeSet,
+    },
+    Digest {
+        digest: RangeSet,
+    },
+    DigestOk {
+        digest: RangeSet,
+    },
+    IHave {
+        messages: RangeSet,
+    },
+    Graft {
+        messages: RangeSet,
+    },
+    Prune,
+    Heartbeat,
+    Membership(membership::Payload),
+    Snapshot(snapshot::Payload<RangeSet>),
+}
+
+pub enum InjectedPayload {
+    Gossip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Periodically diff every neighbour against what it is known to have.
+    Gossip,
+    /// Eager push along a self-healing tree, lazy IHAVE to everybody else.
+    Plumtree,
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gossip" => Ok(Mode::Gossip),
+            "plumtree" => Ok(Mode::Plumtree),
+            _ => Err(anyhow::anyhow!("unknown broadcast mode {s}")),
+        }
+    }
+}
+
+/// Peer state for plumtree. `eager` starts out as the topology neighbours and
+/// `lazy` as a few random other nodes; duplicates prune eager links and
+/// missing IHAVEs graft lazy ones back in.
+struct Plumtree {
+    eager: HashSet<String>,
+    lazy: HashSet<String>,
+    lazy_queue: HashMap<String, RangeSet>,
+    missing: HashMap<usize, (String, Instant)>,
+    /// Who delivered each recent message first, and when.
+    delivered_by: HashMap<usize, (String, Instant)>,
+}
+
+impl Plumtree {
+    fn new(node: &str, node_ids: &[String], neighbours: &[String]) -> Self {
+        let mut plumtree = Plumtree {
+            eager: HashSet::new(),
+            lazy: HashSet::new(),
+            lazy_queue: HashMap::new(),
+            missing: HashMap::new(),
+            delivered_by: HashMap::new(),
+        };
+        plumtree.reset(node, node_ids, neighbours);
+        plumtree
+    }
+
+    fn reset(&mut self, node: &str, node_ids: &[String], neighbours: &[String]) {
+        self.eager = neighbours.iter().cloned().collect();
+        let mut others: Vec<String> = node_ids
+            .iter()
+            .filter(|n| n.as_str() != node && !self.eager.contains(*n))
+            .cloned()
+            .collect();
+        Rng::for_node(node).shuffle(&mut others);
+        self.lazy = others.into_iter().take(LAZY_FANOUT).collect();
+    }
+
+    fn make_eager(&mut self, peer: &str) {
+        self.lazy.remove(peer);
+        self.eager.insert(peer.to_string());
+    }
+
+    fn make_lazy(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.insert(peer.to_string());
+    }
+
+    fn forget(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.remove(peer);
+        self.lazy_queue.remove(peer);
+    }
+
+    fn announce(&mut self, messages: &RangeSet, except: Option<&str>) {
+        for peer in &self.lazy {
+            if Some(peer.as_str()) == except {
+                continue;
+            }
+            self.lazy_queue
+                .entry(peer.clone())
+                .or_default()
+                .union(messages);
+        }
+    }
+
+    /// Announced messages that did not show up in time, grouped by announcer.
+    fn overdue(&mut self, messages: &RangeSet) -> HashMap<String, RangeSet> {
+        self.missing.retain(|m, _| !messages.contains(*m));
+        let now = Instant::now();
+        let mut grafts: HashMap<String, RangeSet> = HashMap::new();
+        for (message, (announcer, since)) in self.missing.iter_mut() {
+            if now.duration_since(*since) >= GRAFT_TIMEOUT {
+                grafts
+                    .entry(announcer.clone())
+                    .or_default()
+                    .insert(*message);
+                *since = now;
+            }
+        }
+        grafts
+    }
+
+    /// Forget first deliveries past [`DELIVERED_WINDOW`]; a duplicate after
+    /// that is redundant whoever sends it.
+    fn expire_delivered(&mut self) {
+        self.delivered_by
+            .retain(|_, (_, at)| at.elapsed() < DELIVERED_WINDOW);
+    }
+}
+
+/// Broadcast/gossip node over any [`Item`]. Values live in `messages`, keyed
+/// by id; everything exchanged between peers besides the values themselves
+/// (`known`, digests, IHAVE) is a [`RangeSet`] of ids.
+pub struct BroadcastNode<T> {
+    node: String,
+    id: usize,
+    messages: BTreeMap<usize, T>,
+    ids: RangeSet,
+    known: HashMap<String, RangeSet>,
+    node_ids: Vec<String>,
+    strategy: Strategy,
+    neighbours: Vec<String>,
+    msg_communicated: InFlight<RangeSet>,
+    plumtree: Option<Plumtree>,
+    membership: Option<HyParView>,
+    detector: FailureDetector,
+    ticks: usize,
+    /// Snapshots are all our messages, versioned by their ids.
+    snapshots: Transfers<RangeSet, Vec<T>>,
+}
+
+impl<T: Item> BroadcastNode<T> {
+    pub fn messages(&self) -> impl Iterator<Item = &T> {
+        self.messages.values()
+    }
+
+    /// Store `message`, returning whether it was new. Fails if a different
+    /// message already holds its id: peers would never tell the two apart.
+    fn deliver(&mut self, message: T) -> anyhow::Result<bool> {
+        let id = message.id();
+        if let Some(known) = self.messages.get(&id) {
+            anyhow::ensure!(
+                known == &message,
+                "message id {id} collides with a different message"
+            );
+            return Ok(false);
+        }
+        self.ids.insert(id);
+        self.messages.insert(id, message);
+        Ok(true)
+    }
+
+    fn gossip_message(&self, dest: &str, ids: &RangeSet) -> Message<Payload<T>> {
+        let new_messages = ids
+            .iter()
+            .filter_map(|id| self.messages.get(&id).cloned())
+            .collect();
+        Message {
+            dest: dest.to_string(),
+            src: self.node.clone(),
+            body: Body {
+                payload: Payload::Gossip { new_messages },
+                id: Some(self.id),
+                in_reply_to: None,
+            },
+        }
+    }
+
+    fn gossip(&mut self, dest: &str, ids: RangeSet, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let message = self.gossip_message(dest, &ids);
+        self.msg_communicated.track(self.id, dest.to_string(), ids);
+        self.id += 1;
+        self.send(&message, output)
+    }
+
+    /// Resend timed out gossip, trimmed to what the peer is still not known to
+    /// have. Entries the peer has since acknowledged by other means are gone.
+    fn retransmit(&mut self, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let known = &self.known;
+        self.msg_communicated.retain(|e| {
+            known
+                .get(&e.dest)
+                .is_none_or(|k| !e.data.difference(k).is_empty())
+        });
+        for entry in self.msg_communicated.expired() {
+            if !self.detector.is_alive(&entry.dest) {
+                // Not worth retrying into the void; the periodic diff picks
+                // these up again once the peer is back.
+                continue;
+            }
+            let data = match self.known.get(&entry.dest) {
+                Some(known) => entry.data.difference(known),
+                None => entry.data.clone(),
+            };
+            let message = self.gossip_message(&entry.dest, &data);
+            self.msg_communicated
+                .retry(self.id, Entry { data, ..entry });
+            self.id += 1;
+            self.send(&message, output)?;
+        }
+        Ok(())
+    }
+
+    fn send_to(
+        &mut self,
+        dest: &str,
+        payload: Payload<T>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let message = Message {
+            dest: dest.to_string(),
+            src: self.node.clone(),
+            body: Body {
+                payload,
+                id: Some(self.id),
+                in_reply_to: None,
+            },
+        };
+        self.id += 1;
+        self.send(&message, output)
+    }
+
+    /// Plumtree: push freshly delivered messages down the tree right away and
+    /// queue IHAVEs for the lazy peers.
+    fn eager_push(
+        &mut self,
+        messages: &RangeSet,
+        from: Option<&str>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        let Some(plumtree) = &mut self.plumtree else {
+            return Ok(());
+        };
+        plumtree.announce(messages, from);
+        let eager: Vec<String> = plumtree
+            .eager
+            .iter()
+            .filter(|p| Some(p.as_str()) != from && self.detector.is_alive(p))
+            .cloned()
+            .collect();
+        for peer in eager {
+            self.gossip(&peer, messages.clone(), output)?;
+        }
+        Ok(())
+    }
+
+    /// Send what the membership layer produced and fold its view changes into
+    /// the neighbour list (and the plumtree peers).
+    fn membership_out(
+        &mut self,
+        out: Vec<(String, membership::Payload)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for (dest, payload) in out {
+            self.send_to(&dest, Payload::Membership(payload), output)?;
+        }
+        let Some(membership) = &mut self.membership else {
+            return Ok(());
+        };
+        for change in membership.take_changes() {
+            match change {
+                Change::Up(peer) => {
+                    if let Some(plumtree) = &mut self.plumtree {
+                        plumtree.make_eager(&peer);
+                    }
+                    if !self.neighbours.contains(&peer) {
+                        self.neighbours.push(peer);
+                    }
+                }
+                Change::Down(peer) => {
+                    if let Some(plumtree) = &mut self.plumtree {
+                        plumtree.forget(&peer);
+                    }
+                    self.neighbours.retain(|n| n != &peer);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Peers the periodic diff goes to: the topology neighbours, or the eager
+    /// peers when running plumtree, less those the failure detector suspects.
+    fn gossip_targets(&self) -> Vec<String> {
+        let targets = match &self.plumtree {
+            Some(plumtree) => plumtree.eager.iter().cloned().collect(),
+            None => self.neighbours.clone(),
+        };
+        targets
+            .into_iter()
+            .filter(|p| self.detector.is_alive(p))
+            .collect()
+    }
+
+    /// Everyone we might talk to, and so want heartbeats from.
+    fn peers(&self) -> HashSet<String> {
+        let mut peers: HashSet<String> = self.neighbours.iter().cloned().collect();
+        if let Some(plumtree) = &self.plumtree {
+            peers.extend(plumtree.eager.iter().cloned());
+            peers.extend(plumtree.lazy.iter().cloned());
+        }
+        peers.remove(&self.node);
+        peers
+    }
+
+    /// Push whatever `peer` is not known to have, if anything. A snapshot on
+    /// its way there covers everything already.
+    fn sync(&mut self, peer: &str, output: &mut StdoutLock) -> anyhow::Result<()> {
+        if self.snapshots.sending(peer).is_some() {
+            return Ok(());
+        }
+        let mut pending = self.known.entry(peer.to_string()).or_default().clone();
+        for in_flight in self.msg_communicated.to(peer) {
+            pending.union(in_flight);
+        }
+        let new_messages = self.ids.difference(&pending);
+        if new_messages.is_empty() || self.node == peer {
+            return Ok(());
+        }
+        self.gossip(peer, new_messages, output)
+    }
+
+    /// After swapping digests with `peer`: stream it a snapshot if it is far
+    /// behind, otherwise gossip the difference.
+    fn catch_up(&mut self, peer: &str, output: &mut StdoutLock) -> anyhow::Result<()> {
+        let missing = match self.known.get(peer) {
+            Some(known) => self.ids.difference(known).len(),
+            None => self.ids.len(),
+        };
+        if missing < SNAPSHOT_THRESHOLD || self.node == peer {
+            return self.sync(peer, output);
+        }
+        let state: Vec<T> = self.messages.values().cloned().collect();
+        let out = self.snapshots.send(peer, self.ids.clone(), &state)?;
+        self.snapshot_out(out, output)
+    }
+
+    fn snapshot_out(
+        &mut self,
+        out: Vec<(String, snapshot::Payload<RangeSet>)>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        for (dest, payload) in out {
+            self.send_to(&dest, Payload::Snapshot(payload), output)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Item> Node<Payload<T>, InjectedPayload> for BroadcastNode<T> {
+    fn step(
+        &mut self,
+        event: Event<Payload<T>, InjectedPayload>,
+        output: &mut StdoutLock,
+    ) -> anyhow::Result<()> {
+        if let Event::Message(input) = &event {
+            if self.node_ids.contains(&input.src) {
+                self.detector.heartbeat(&input.src);
+            }
+        }
+        match &event {
+            Event::Message(input) => match &input.body.payload {
+                Payload::Read => {
+                    let reply = input.construct_reply(
+                        Payload::ReadOk {
+                            messages: self.messages.values().cloned().collect(),
+                        },
+                        Some(&mut self.id),
+                    );
+                    self.send(&reply, output)?;
+                }
+                Payload::Broadcast { message } => {
+                    let is_new = self.deliver(message.clone())?;
+                    let reply = input.construct_reply(Payload::BroadcastOk, Some(&mut self.id));
+                    self.send(&reply, output)?;
+                    if is_new {
+                        self.eager_push(&RangeSet::from_iter([message.id()]), None, output)?;
+                    }
+                }
+                Payload::Topology { topology } => {
+                    if self.strategy.needs_given() && self.membership.is_none() {
+                        self.neighbours =
+                            self.strategy
+                                .neighbours(&self.node, &self.node_ids, Some(topology));
+                        if let Some(plumtree) = &mut self.plumtree {
+                            plumtree.reset(&self.node, &self.node_ids, &self.neighbours);
+                        }
+                    }
+                    let reply = input.construct_reply(Payload::TopologyOk, Some(&mut self.id));
+                    self.send(&reply, output)?;
+                }
+                Payload::ReadOk { .. } | Payload::BroadcastOk | Payload::TopologyOk => {}
+                Payload::Gossip { new_messages } => {
+                    let mut received = RangeSet::new();
+                    let mut fresh = RangeSet::new();
+                    for message in new_messages {
+                        received.insert(message.id());
+                        if self.deliver(message.clone())? {
+                            fresh.insert(message.id());
+                        }
+                    }
+                    self.known
+                        .entry(input.src.clone())
+                        .or_default()
+                        .union(&received);
+                    // Only our summary goes back; whatever the sender is
+                    // missing follows as regular gossip.
+                    let reply = input.construct_reply(
+                        Payload::GossipOk {
+                            digest: self.ids.clone(),
+                        },
+                        Some(&mut self.id),
+                    );
+                    self.send(&reply, output)?;
+                    if let Some(plumtree) = &mut self.plumtree {
+                        if fresh.is_empty() {
+                            // Everything was a duplicate that some other peer
+                            // delivered first: there is a cycle, so drop this
+                            // link out of the tree. Retransmissions from the
+                            // peer that delivered first are not duplicates.
+                            let redundant = received.iter().all(|m| {
+                                plumtree
+                                    .delivered_by
+                                    .get(&m)
+                                    .is_none_or(|(peer, _)| peer != &input.src)
+                            });
+                            if redundant && plumtree.eager.contains(&input.src) {
+                                plumtree.make_lazy(&input.src);
+                                self.send_to(&input.src, Payload::Prune, output)?;
+                            }
+                        } else {
+                            plumtree.make_eager(&input.src);
+                            let now = Instant::now();
+                            for message in fresh.iter() {
+                                plumtree
+                                    .delivered_by
+                                    .insert(message, (input.src.clone(), now));
+                            }
+                            self.eager_push(&fresh, Some(&input.src), output)?;
+                        }
+                    }
+                }
+                Payload::GossipOk { digest } => {
+                    let known = self.known.entry(input.src.clone()).or_default();
+                    known.union(digest);
+                    if let Some(in_reply_to) = &input.body.in_reply_to {
+                        if let Some(communicated) = self.msg_communicated.ack(*in_reply_to) {
+                            known.union(&communicated.data);
+                        }
+                    }
+                }
+                Payload::Digest { digest } => {
+                    // A digest is everything the peer has, so it replaces
+                    // what we assumed: a peer that restarted may have lost
+                    // messages we saw it acknowledge.
+                    self.known.insert(input.src.clone(), digest.clone());
+                    let reply = input.construct_reply(
+                        Payload::DigestOk {
+                            digest: self.ids.clone(),
+                        },
+                        Some(&mut self.id),
+                    );
+                    self.send(&reply, output)?;
+                    self.catch_up(&input.src, output)?;
+                }
+                Payload::DigestOk { digest } => {
+                    self.known.insert(input.src.clone(), digest.clone());
+                    self.catch_up(&input.src, output)?;
+                }
+                Payload::IHave { messages } => {
+                    if let Some(plumtree) = &mut self.plumtree {
+                        let now = Instant::now();
+                        for message in messages.difference(&self.ids).iter() {
+                            plumtree
+                                .missing
+                                .entry(message)
+                                .or_insert_with(|| (input.src.clone(), now));
+                        }
+                        self.known
+                            .entry(input.src.clone())
+                            .or_default()
+                            .union(messages);
+                    }
+                }
+                Payload::Graft { messages } => {
+                    if let Some(plumtree) = &mut self.plumtree {
+                        plumtree.make_eager(&input.src);
+                        let available = messages.intersection(&self.ids);
+                        if !available.is_empty() {
+                            self.gossip(&input.src, available, output)?;
+                        }
+                    }
+                }
+                Payload::Prune => {
+                    if let Some(plumtree) = &mut self.plumtree {
+                        plumtree.make_lazy(&input.src);
+                    }
+                }
+                Payload::Heartbeat => {}
+                Payload::Membership(payload) => {
+                    if let Some(membership) = &mut self.membership {
+                        let out = membership.handle(&input.src, payload);
+                        self.membership_out(out, output)?;
+                    }
+                }
+                Payload::Snapshot(payload) => {
+                    let out = self.snapshots.handle(&input.src, payload);
+                    self.snapshot_out(out, output)?;
+                    for installed in self.snapshots.take_installed() {
+                        // Messages only ever accumulate, so installing a
+                        // snapshot is delivering everything in it.
+                        let mut fresh = RangeSet::new();
+                        for message in installed.state {
+                            let id = message.id();
+                            if self.deliver(message)? {
+                                fresh.insert(id);
+                            }
+                        }
+                        self.known
+                            .entry(installed.from.clone())
+                            .or_default()
+                            .union(&installed.version);
+                        if !fresh.is_empty() {
+                            self.eager_push(&fresh, Some(&installed.from), output)?;
+                        }
+                    }
+                }
+            },
+            Event::InjectedPayload(payload) => match &payload {
+                InjectedPayload::Gossip => {
+                    self.ticks += 1;
+                    if let Some(membership) = &mut self.membership {
+                        let out = if self.ticks == 1 {
+                            let contact = self.node_ids.iter().min().cloned().unwrap_or_default();
+                            membership.join(&contact)
+                        } else {
+                            membership.tick()
+                        };
+                        self.membership_out(out, output)?;
+                    }
+                    if self.detector.is_enabled() {
+                        for peer in self.peers() {
+                            self.send_to(&peer, Payload::Heartbeat, output)?;
+                        }
+                    }
+                    for suspicion in self.detector.poll() {
+                        let Suspicion::Suspected(peer) = suspicion else {
+                            continue;
+                        };
+                        if let Some(membership) = &mut self.membership {
+                            let out = membership.peer_failed(&peer);
+                            self.membership_out(out, output)?;
+                        }
+                    }
+                    self.retransmit(output)?;
+                    let out = self.snapshots.tick();
+                    self.snapshot_out(out, output)?;
+                    for neighbour in self.gossip_targets() {
+                        if self.ticks.is_multiple_of(DIGEST_EVERY) && self.node != neighbour {
+                            // Anti-entropy: `known` can lag (lost acks, other
+                            // paths), so swap summaries and let each side push
+                            // only what the other is missing.
+                            let digest = self.ids.clone();
+                            self.send_to(&neighbour, Payload::Digest { digest }, output)?;
+                            continue;
+                        }
+                        self.sync(&neighbour, output)?;
+                    }
+                    let Some(plumtree) = &mut self.plumtree else {
+                        return Ok(());
+                    };
+                    let announcements: Vec<(String, RangeSet)> =
+                        plumtree.lazy_queue.drain().collect();
+                    let grafts = plumtree.overdue(&self.ids);
+                    plumtree.expire_delivered();
+                    for peer in grafts.keys() {
+                        plumtree.make_eager(peer);
+                    }
+                    for (peer, messages) in grafts {
+                        self.send_to(&peer, Payload::Graft { messages }, output)?;
+                    }
+                    for (peer, messages) in announcements {
+                        if messages.is_empty() {
+                            continue;
+                        }
+                        self.send_to(&peer, Payload::IHave { messages }, output)?;
+                    }
+                }
+            },
+            Event::EOF => {}
+        }
+        Ok(())
+    }
+
+    fn from_init(
+        init: Init,
+        tx: std::sync::mpsc::Sender<Event<Payload<T>, InjectedPayload>>,
+    ) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        let strategy = Strategy::from_env()?;
+        let mode = match std::env::var(MODE_ENV) {
+            Ok(mode) => mode.parse()?,
+            Err(_) => Mode::Gossip,
+        };
+        std::thread::spawn(move || {
+            // TODO: Handle EOF
+            loop {
+                std::thread::sleep(Duration::from_millis(200));
+                if tx
+                    .send(Event::InjectedPayload(InjectedPayload::Gossip))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        // With HyParView the neighbours are whatever the active view holds.
+        let membership = membership::enabled().then(|| {
+            HyParView::new(
+                &init.node_id,
+                &init.node_ids,
+                membership::Config::for_cluster(init.node_ids.len()),
+            )
+        });
+        // HyParView repairs its view off the detector's suspicions.
+        let detector = match membership {
+            Some(_) => FailureDetector::default(),
+            None => FailureDetector::from_env(),
+        };
+        let neighbours = match membership {
+            Some(_) => Vec::new(),
+            None => strategy.neighbours(&init.node_id, &init.node_ids, None),
+        };
+        let plumtree = match mode {
+            Mode::Plumtree => Some(Plumtree::new(&init.node_id, &init.node_ids, &neighbours)),
+            Mode::Gossip => None,
+        };
+        let node = BroadcastNode {
+            snapshots: Transfers::new(&init.node_id, snapshot::Config::default()),
+            node: init.node_id,
+            id: 1,
+            messages: BTreeMap::new(),
+            ids: RangeSet::new(),
+            known: init
+                .node_ids
+                .iter()
+                .map(|nid| (nid.clone(), RangeSet::new()))
+                .collect(),
+            msg_communicated: InFlight::new(GOSSIP_TIMEOUT, GOSSIP_ATTEMPTS, IN_FLIGHT_CAPACITY),
+            node_ids: init.node_ids,
+            strategy,
+            neighbours,
+            plumtree,
+            membership,
+            detector,
+            ticks: 0,
+        };
+        Ok(node)
+    }
+}
@@ -0,0 +1,89 @@
+//! Invariants checked against client traffic while a [`crate::cluster::Cluster`]
+//! runs, so process faults that corrupt node state show up as violations
+//! rather than as a silently wrong answer.
+
+use std::collections::HashMap;
+
+use anyhow::bail;
+use serde_json::Value;
+
+pub trait Checker {
+    /// Called with every request a client sent to `node` and the reply it
+    /// got back. An error is a violation of the checked invariant.
+    fn observe(&mut self, node: &str, request: &Value, reply: &Value) -> anyhow::Result<()>;
+}
+
+/// k-log: an offset, once handed out for a key, always holds the same
+/// message, whether it is seen in a `send_ok` or in a `poll_ok`.
+#[derive(Debug, Default)]
+pub struct OffsetsNeverReused {
+    messages: HashMap<(String, u64), Value>,
+}
+
+impl OffsetsNeverReused {
+    fn record(&mut self, key: &str, offset: u64, msg: &Value) -> anyhow::Result<()> {
+        match self.messages.get(&(key.to_string(), offset)) {
+            Some(seen) if seen != msg => {
+                bail!("offset {offset} of {key} holds {msg}, but earlier held {seen}")
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.messages.insert((key.to_string(), offset), msg.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Checker for OffsetsNeverReused {
+    fn observe(&mut self, _node: &str, request: &Value, reply: &Value) -> anyhow::Result<()> {
+        match reply["type"].as_str() {
+            Some("send_ok") => {
+                let (Some(key), Some(offset)) = (request["key"].as_str(), reply["offset"].as_u64())
+                else {
+                    bail!("malformed send_ok {reply} to {request}");
+                };
+                self.record(key, offset, &request["msg"])
+            }
+            Some("poll_ok") => {
+                let Some(msgs) = reply["msgs"].as_object() else {
+                    bail!("malformed poll_ok {reply}");
+                };
+                for (key, entries) in msgs {
+                    for entry in entries.as_array().into_iter().flatten() {
+                        let Some(offset) = entry[0].as_u64() else {
+                            bail!("malformed poll_ok entry {entry}");
+                        };
+                        self.record(key, offset, &entry[1])?;
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// g-counter: no node ever reads lower than it read before, restarts
+/// included.
+#[derive(Debug, Default)]
+pub struct NeverDecreasing {
+    last_read: HashMap<String, u64>,
+}
+
+impl Checker for NeverDecreasing {
+    fn observe(&mut self, node: &str, _request: &Value, reply: &Value) -> anyhow::Result<()> {
+        if reply["type"] != "read_ok" {
+            return Ok(());
+        }
+        let Some(value) = reply["value"].as_u64() else {
+            bail!("malformed read_ok {reply}");
+        };
+        // Every drop is reported once, not every read until it recovers.
+        let last = std::mem::replace(self.last_read.entry(node.to_string()).or_default(), value);
+        if value < last {
+            bail!("{node} read {value} after reading {last}");
+        }
+        Ok(())
+    }
+}
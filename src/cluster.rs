@@ -0,0 +1,246 @@
+//! A local stand-in for maelstrom, enough to run a workload's nodes as
+//! processes, act as their client, and crash and restart them.
+//!
+//! Messages between nodes are routed as-is; `lin-kv`, `seq-kv` and `lww-kv`
+//! are served by a [`Store`] per service that outlives any node. Every reply
+//! a client gets is handed to the registered [`Checker`]s.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use serde_json::{json, Value};
+
+use crate::{
+    checker::Checker,
+    consensus::StateMachine,
+    kv::{self, Store},
+    Body, Message,
+};
+
+/// Name the cluster's client goes by.
+pub const CLIENT: &str = "c1";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a stopped node gets to act on EOF before it is killed.
+const STOP_GRACE: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// SIGKILL: whatever the node wrote but had not synced is lost.
+    Kill,
+    /// Close stdin so the node sees EOF and syncs, then kill it.
+    Stop,
+}
+
+type Inputs = Arc<Mutex<HashMap<String, ChildStdin>>>;
+
+pub struct Cluster {
+    binary: PathBuf,
+    node_ids: Vec<String>,
+    env: Vec<(String, String)>,
+    children: HashMap<String, Child>,
+    /// stdin of every running node; messages to anyone else are dropped.
+    inputs: Inputs,
+    router: Sender<Message<Value>>,
+    replies: Receiver<Message<Value>>,
+    id: usize,
+    checkers: Vec<Box<dyn Checker>>,
+    violations: Vec<String>,
+}
+
+impl Cluster {
+    /// Start `binary` once per node id, with `env` on top of our own
+    /// environment.
+    pub fn new(
+        binary: impl Into<PathBuf>,
+        node_ids: Vec<String>,
+        env: Vec<(String, String)>,
+    ) -> anyhow::Result<Self> {
+        let (router, messages) = channel();
+        let (to_client, replies) = channel();
+        let inputs = Inputs::default();
+        let route_inputs = inputs.clone();
+        std::thread::spawn(move || route(messages, route_inputs, to_client));
+        let mut cluster = Cluster {
+            binary: binary.into(),
+            node_ids,
+            env,
+            children: HashMap::new(),
+            inputs,
+            router,
+            replies,
+            id: 0,
+            checkers: Vec::new(),
+            violations: Vec::new(),
+        };
+        for node in cluster.node_ids.clone() {
+            cluster.start(&node)?;
+        }
+        Ok(cluster)
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    pub fn check(&mut self, checker: impl Checker + 'static) {
+        self.checkers.push(Box::new(checker));
+    }
+
+    /// Everything the checkers objected to so far.
+    pub fn violations(&self) -> &[String] {
+        &self.violations
+    }
+
+    /// Spawn `node` and send it `init`. A node that was crashed comes back
+    /// with the same id and environment, so it finds its storage again.
+    pub fn start(&mut self, node: &str) -> anyhow::Result<()> {
+        let mut child = Command::new(&self.binary)
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("spawn {}", self.binary.display()))?;
+        let stdin = child.stdin.take().context("node stdin")?;
+        let stdout = child.stdout.take().context("node stdout")?;
+        let router = self.router.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                match serde_json::from_str(&line) {
+                    Ok(message) => {
+                        if router.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => eprintln!("unparseable node output {line}: {e}"),
+                }
+            }
+        });
+        self.inputs.lock().unwrap().insert(node.to_string(), stdin);
+        self.children.insert(node.to_string(), child);
+        let init = json!({
+            "type": "init",
+            "node_id": node,
+            "node_ids": self.node_ids,
+        });
+        if self.request(node, init)?.is_none() {
+            bail!("{node} did not answer init");
+        }
+        Ok(())
+    }
+
+    pub fn crash(&mut self, node: &str, fault: Fault) -> anyhow::Result<()> {
+        let stdin = self.inputs.lock().unwrap().remove(node);
+        let Some(mut child) = self.children.remove(node) else {
+            bail!("{node} is not running");
+        };
+        if fault == Fault::Stop {
+            drop(stdin);
+            std::thread::sleep(STOP_GRACE);
+        }
+        child.kill().context("kill node")?;
+        child.wait().context("wait for node")?;
+        Ok(())
+    }
+
+    /// Send `payload` to `node` as a client and wait for the reply's
+    /// payload, or `None` if it timed out.
+    pub fn request(&mut self, node: &str, payload: Value) -> anyhow::Result<Option<Value>> {
+        self.id += 1;
+        let message = Message {
+            src: CLIENT.to_string(),
+            dest: node.to_string(),
+            body: Body {
+                id: Some(self.id),
+                in_reply_to: None,
+                payload: payload.clone(),
+            },
+        };
+        deliver(&self.inputs, &message)?;
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let reply = loop {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Ok(None);
+            };
+            match self.replies.recv_timeout(left) {
+                // Replies to requests that already timed out are dropped.
+                Ok(reply) if reply.body.in_reply_to == Some(self.id) => break reply,
+                Ok(_) => {}
+                Err(_) => return Ok(None),
+            }
+        };
+        for checker in &mut self.checkers {
+            if let Err(e) = checker.observe(node, &payload, &reply.body.payload) {
+                self.violations.push(e.to_string());
+            }
+        }
+        Ok(Some(reply.body.payload))
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for child in self.children.values_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn deliver(inputs: &Inputs, message: &Message<Value>) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message).context("serialize message")?;
+    line.push(b'\n');
+    if let Some(stdin) = inputs.lock().unwrap().get_mut(&message.dest) {
+        // A node that just died is the same as a dropped message.
+        let _ = stdin.write_all(&line);
+    }
+    Ok(())
+}
+
+fn route(messages: Receiver<Message<Value>>, inputs: Inputs, to_client: Sender<Message<Value>>) {
+    let mut services: HashMap<String, Store> = HashMap::new();
+    for message in messages {
+        let dest = message.dest.as_str();
+        if dest == CLIENT {
+            let _ = to_client.send(message);
+            continue;
+        }
+        let message = if [kv::LIN_KV, kv::SEQ_KV, kv::LWW_KV].contains(&dest) {
+            let result = match serde_json::from_value(message.body.payload.clone()) {
+                Ok(command) => services
+                    .entry(dest.to_string())
+                    .or_default()
+                    .apply(&command),
+                Err(e) => kv::Payload::Error {
+                    code: kv::NOT_SUPPORTED,
+                    text: e.to_string(),
+                },
+            };
+            Message {
+                src: message.dest,
+                dest: message.src,
+                body: Body {
+                    id: None,
+                    in_reply_to: message.body.id,
+                    payload: serde_json::to_value(result).expect("kv payloads serialize"),
+                },
+            }
+        } else {
+            message
+        };
+        if let Err(e) = deliver(&inputs, &message) {
+            eprintln!("could not route {message:?}: {e}");
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Replicated state machines.
+//!
+//! A [`Consensus`] implementation orders [`Proposal`]s into a log and applies
+//! them to a [`StateMachine`] on every node in the same order. Like the other
+//! library components they are sans-IO: calls return `(dest, Payload)` pairs
+//! and the node wraps them into its own payload type.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub const CONSENSUS_ENV: &str = "RUSTENGAN_CONSENSUS";
+
+pub trait StateMachine {
+    type Command: Clone + Serialize + DeserializeOwned;
+    type Output;
+
+    /// Must be deterministic: every replica applies the same commands in the
+    /// same order and has to end up in the same state.
+    fn apply(&mut self, command: &Self::Command) -> Self::Output;
+}
+
+/// A command together with who asked for it. Only `origin`, the node the
+/// client talked to, answers the client once the command is applied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proposal<C> {
+    pub origin: String,
+    pub client: String,
+    pub msg_id: Option<usize>,
+    pub command: C,
+}
+
+pub trait Consensus<S: StateMachine> {
+    type Payload;
+
+    /// Start replicating `proposal`. Fails with the leader we know of, if
+    /// any, when this node cannot order commands itself.
+    fn propose(
+        &mut self,
+        proposal: Proposal<S::Command>,
+    ) -> Result<Vec<(String, Self::Payload)>, Option<String>>;
+
+    fn handle(&mut self, src: &str, payload: Self::Payload) -> Vec<(String, Self::Payload)>;
+
+    /// Timers: heartbeats, elections, retransmissions.
+    fn tick(&mut self) -> Vec<(String, Self::Payload)>;
+
+    /// Proposals applied since the last call, with their outputs, in log
+    /// order.
+    fn take_applied(&mut self) -> Vec<(Proposal<S::Command>, S::Output)>;
+
+    fn leader(&self) -> Option<&str>;
+
+    fn state_machine(&self) -> &S;
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Set of integers stored as sorted, non-overlapping inclusive ranges.
+///
+/// Gossiped ids tend to be dense, so this stays a handful of pairs where a
+/// `Vec<usize>` would grow with every message. On the wire it is a list of
+/// `[start, end]` pairs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<(usize, usize)>", into = "Vec<(usize, usize)>")]
+pub struct RangeSet {
+    ranges: BTreeMap<usize, usize>,
+}
+
+impl RangeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Number of integers in the set, not the number of ranges.
+    pub fn len(&self) -> usize {
+        self.ranges.iter().map(|(s, e)| e - s + 1).sum()
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, end)| *end >= value)
+    }
+
+    /// Returns whether `value` was not already present.
+    pub fn insert(&mut self, value: usize) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+        self.insert_range(value, value);
+        true
+    }
+
+    pub fn insert_range(&mut self, start: usize, end: usize) {
+        let (mut start, mut end) = (start.min(end), start.max(end));
+        if let Some((&s, &e)) = self.ranges.range(..=start).next_back() {
+            if e.saturating_add(1) >= start {
+                start = s;
+                end = end.max(e);
+                self.ranges.remove(&s);
+            }
+        }
+        let absorbed: Vec<(usize, usize)> = self
+            .ranges
+            .range(start..=end.saturating_add(1))
+            .map(|(s, e)| (*s, *e))
+            .collect();
+        for (s, e) in absorbed {
+            end = end.max(e);
+            self.ranges.remove(&s);
+        }
+        self.ranges.insert(start, end);
+    }
+
+    pub fn union(&mut self, other: &RangeSet) {
+        for (s, e) in other.ranges() {
+            self.insert_range(s, e);
+        }
+    }
+
+    /// Everything in `self` that is not in `other`.
+    pub fn difference(&self, other: &RangeSet) -> RangeSet {
+        let mut result = RangeSet::new();
+        for (start, end) in self.ranges() {
+            let mut cursor = start;
+            let first = other
+                .ranges
+                .range(..=start)
+                .next_back()
+                .map(|(s, _)| *s)
+                .unwrap_or(start);
+            let mut exhausted = false;
+            for (&os, &oe) in other.ranges.range(first..=end) {
+                if oe < cursor {
+                    continue;
+                }
+                if os > cursor {
+                    result.insert_range(cursor, os - 1);
+                }
+                if oe >= end {
+                    exhausted = true;
+                    break;
+                }
+                cursor = oe + 1;
+            }
+            if !exhausted {
+                result.insert_range(cursor, end);
+            }
+        }
+        result
+    }
+
+    /// Everything in both `self` and `other`.
+    pub fn intersection(&self, other: &RangeSet) -> RangeSet {
+        self.difference(&self.difference(other))
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.ranges.iter().map(|(s, e)| (*s, *e))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ranges().flat_map(|(s, e)| s..=e)
+    }
+}
+
+impl FromIterator<usize> for RangeSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut set = RangeSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl Extend<usize> for RangeSet {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl<'a> Extend<&'a usize> for RangeSet {
+    fn extend<I: IntoIterator<Item = &'a usize>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().copied());
+    }
+}
+
+impl From<Vec<(usize, usize)>> for RangeSet {
+    fn from(ranges: Vec<(usize, usize)>) -> Self {
+        let mut set = RangeSet::new();
+        for (s, e) in ranges {
+            set.insert_range(s, e);
+        }
+        set
+    }
+}
+
+impl From<RangeSet> for Vec<(usize, usize)> {
+    fn from(set: RangeSet) -> Self {
+        set.ranges().collect()
+    }
+}
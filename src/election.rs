@@ -0,0 +1,367 @@
+//! Leader election.
+//!
+//! Two implementations behind one [`Election`] trait:
+//!
+//! * [`Bully`] runs inside the cluster: the highest node id the failure
+//!   detector considers alive wins.
+//! * [`Lease`] stores a lease with an expiry in `lin-kv` and renews it with
+//!   CAS, so at most one node holds it at any time (assuming nodes share a
+//!   clock, which they do under maelstrom).
+//!
+//! Both are sans-IO like the membership layer and report leader changes
+//! through [`Election::take_changes`].
+
+use std::{
+    cmp::Ordering,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{cmp_node_ids, failure_detector::FailureDetector, kv, Body, Message};
+
+/// The leader as of this change, `None` while nobody is known to lead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderChanged(pub Option<String>);
+
+pub trait Election {
+    fn leader(&self) -> Option<&str>;
+    fn is_leader(&self) -> bool;
+    /// Leader changes since the last call.
+    fn take_changes(&mut self) -> Vec<LeaderChanged>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Election,
+    Answer,
+    Coordinator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BullyState {
+    Idle,
+    /// Sent `Election` to everyone above us, waiting for an `Answer`.
+    Electing(Instant),
+    /// Someone above us answered, waiting for their `Coordinator`.
+    Waiting(Instant),
+}
+
+pub struct Bully {
+    me: String,
+    nodes: Vec<String>,
+    leader: Option<String>,
+    reported: Option<String>,
+    state: BullyState,
+    timeout: Duration,
+    changes: Vec<LeaderChanged>,
+}
+
+impl Bully {
+    pub fn new(me: &str, node_ids: &[String], timeout: Duration) -> Self {
+        Bully {
+            me: me.to_string(),
+            nodes: node_ids.to_vec(),
+            leader: None,
+            reported: None,
+            state: BullyState::Idle,
+            timeout,
+            changes: Vec::new(),
+        }
+    }
+
+    fn higher(&self) -> impl Iterator<Item = &String> {
+        self.nodes
+            .iter()
+            .filter(|n| cmp_node_ids(n, &self.me) == Ordering::Greater)
+    }
+
+    /// Start an election; call it once at startup and whenever the leader
+    /// goes away.
+    pub fn start(&mut self) -> Vec<(String, Payload)> {
+        let higher: Vec<(String, Payload)> = self
+            .higher()
+            .map(|n| (n.clone(), Payload::Election))
+            .collect();
+        if higher.is_empty() {
+            return self.win();
+        }
+        self.state = BullyState::Electing(Instant::now());
+        higher
+    }
+
+    fn win(&mut self) -> Vec<(String, Payload)> {
+        self.state = BullyState::Idle;
+        self.set_leader(Some(self.me.clone()));
+        self.nodes
+            .iter()
+            .filter(|n| **n != self.me)
+            .map(|n| (n.clone(), Payload::Coordinator))
+            .collect()
+    }
+
+    fn set_leader(&mut self, leader: Option<String>) {
+        self.leader = leader;
+        if self.leader != self.reported {
+            self.reported = self.leader.clone();
+            self.changes.push(LeaderChanged(self.leader.clone()));
+        }
+    }
+
+    pub fn handle(&mut self, src: &str, payload: &Payload) -> Vec<(String, Payload)> {
+        match payload {
+            Payload::Election => {
+                // A lower node is electing; bully it and run our own round
+                // unless one is already going.
+                let mut out = vec![(src.to_string(), Payload::Answer)];
+                if self.state == BullyState::Idle && !self.is_leader() {
+                    out.extend(self.start());
+                } else if self.is_leader() {
+                    out.push((src.to_string(), Payload::Coordinator));
+                }
+                out
+            }
+            Payload::Answer => {
+                if let BullyState::Electing(_) = self.state {
+                    self.state = BullyState::Waiting(Instant::now());
+                }
+                Vec::new()
+            }
+            Payload::Coordinator => {
+                if cmp_node_ids(src, &self.me) == Ordering::Less {
+                    // A lower node thinks it won; correct it.
+                    return self.start();
+                }
+                self.state = BullyState::Idle;
+                self.set_leader(Some(src.to_string()));
+                Vec::new()
+            }
+        }
+    }
+
+    /// Re-elect when the leader is suspected or a round stalls. A disabled
+    /// `detector` suspects nobody, so a dead leader is never replaced.
+    pub fn tick(&mut self, detector: &FailureDetector) -> Vec<(String, Payload)> {
+        match self.state {
+            BullyState::Electing(since) if since.elapsed() > self.timeout => self.win(),
+            BullyState::Waiting(since) if since.elapsed() > self.timeout => self.start(),
+            BullyState::Idle => match &self.leader {
+                Some(leader) if *leader != self.me && !detector.is_alive(leader) => {
+                    self.set_leader(None);
+                    self.start()
+                }
+                None => self.start(),
+                _ => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Election for Bully {
+    fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    fn is_leader(&self) -> bool {
+        self.leader.as_deref() == Some(self.me.as_str())
+    }
+
+    fn take_changes(&mut self) -> Vec<LeaderChanged> {
+        std::mem::take(&mut self.changes)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Lease held in `lin-kv` under `key` as `{"holder": .., "expires": ms}`.
+///
+/// The holder renews once half the ttl is gone; everyone else re-reads the key
+/// and takes the lease over with CAS once it has expired. A holder stops
+/// calling itself leader `margin` before expiry so a slow renewal never
+/// overlaps with a successor.
+pub struct Lease {
+    me: String,
+    key: String,
+    ttl: Duration,
+    margin: Duration,
+    /// Last value we read or wrote, `Null` when the key does not exist.
+    current: Option<Value>,
+    /// The value our outstanding claim CASes in, installed as is once it
+    /// succeeds so `current` keeps matching lin-kv.
+    claimed: Option<Value>,
+    holder: Option<(String, u64)>,
+    pending: Option<(usize, Instant)>,
+    reported: Option<String>,
+    changes: Vec<LeaderChanged>,
+}
+
+impl Lease {
+    pub fn new(me: &str, key: &str, ttl: Duration) -> Self {
+        Lease {
+            me: me.to_string(),
+            key: key.to_string(),
+            ttl,
+            margin: ttl / 10,
+            current: None,
+            claimed: None,
+            holder: None,
+            pending: None,
+            reported: None,
+            changes: Vec::new(),
+        }
+    }
+
+    fn request(&mut self, payload: kv::Payload, id: &mut usize) -> Message<kv::Payload> {
+        let msg_id = *id;
+        *id += 1;
+        self.pending = Some((msg_id, Instant::now()));
+        Message {
+            src: self.me.clone(),
+            dest: kv::LIN_KV.to_string(),
+            body: Body {
+                id: Some(msg_id),
+                in_reply_to: None,
+                payload,
+            },
+        }
+    }
+
+    fn claim(&mut self, id: &mut usize) -> Message<kv::Payload> {
+        let from = self.current.clone().unwrap_or(Value::Null);
+        let to = json!({ "holder": self.me, "expires": now_ms() + self.ttl.as_millis() as u64 });
+        self.claimed = Some(to.clone());
+        let payload = kv::Payload::Cas {
+            key: self.key.clone().into(),
+            from,
+            to,
+            put: true,
+        };
+        self.request(payload, id)
+    }
+
+    fn read(&mut self, id: &mut usize) -> Message<kv::Payload> {
+        let payload = kv::Payload::Read {
+            key: self.key.clone().into(),
+        };
+        self.request(payload, id)
+    }
+
+    fn parse(value: &Value) -> Option<(String, u64)> {
+        Some((
+            value.get("holder")?.as_str()?.to_string(),
+            value.get("expires")?.as_u64()?,
+        ))
+    }
+
+    fn report(&mut self) {
+        let leader = self.leader().map(str::to_string);
+        if leader != self.reported {
+            self.reported = leader.clone();
+            self.changes.push(LeaderChanged(leader));
+        }
+    }
+
+    /// Whether `in_reply_to` answers our outstanding request.
+    pub fn owns(&self, in_reply_to: Option<usize>) -> bool {
+        self.pending.map(|(id, _)| Some(id)) == Some(in_reply_to)
+    }
+
+    /// Renew, read or take over the lease as needed. At most one request is
+    /// outstanding at a time.
+    pub fn tick(&mut self, id: &mut usize) -> Option<Message<kv::Payload>> {
+        self.report();
+        if let Some((_, since)) = self.pending {
+            if since.elapsed() < self.ttl {
+                return None;
+            }
+            self.pending = None;
+        }
+        let now = now_ms();
+        match &self.holder {
+            Some((holder, expires)) if *holder == self.me => {
+                if expires.saturating_sub(now) < self.ttl.as_millis() as u64 / 2 {
+                    Some(self.claim(id))
+                } else {
+                    None
+                }
+            }
+            Some((_, expires)) if *expires > now => None,
+            Some(_) if self.current.is_some() => Some(self.claim(id)),
+            _ => Some(self.read(id)),
+        }
+    }
+
+    /// Feed a `lin-kv` reply for our outstanding request.
+    pub fn handle(
+        &mut self,
+        in_reply_to: Option<usize>,
+        payload: &kv::Payload,
+        id: &mut usize,
+    ) -> Option<Message<kv::Payload>> {
+        if !self.owns(in_reply_to) {
+            return None;
+        }
+        self.pending = None;
+        let next = match payload {
+            kv::Payload::ReadOk { value } => {
+                self.current = Some(value.clone());
+                self.holder = Self::parse(value);
+                match &self.holder {
+                    Some((_, expires)) if *expires > now_ms() => None,
+                    _ => Some(self.claim(id)),
+                }
+            }
+            kv::Payload::CasOk => {
+                // The expiry we claimed, not a fresh one: lin-kv holds that,
+                // and it started counting before the CAS went out.
+                if let Some(value) = self.claimed.take() {
+                    self.holder = Self::parse(&value);
+                    self.current = Some(value);
+                }
+                None
+            }
+            kv::Payload::Error { code, .. } if *code == kv::KEY_DOES_NOT_EXIST => {
+                self.current = Some(Value::Null);
+                Some(self.claim(id))
+            }
+            kv::Payload::Error { .. } => {
+                // Lost a race or the key changed under us; look again.
+                self.current = None;
+                self.holder = None;
+                Some(self.read(id))
+            }
+            _ => None,
+        };
+        self.report();
+        next
+    }
+}
+
+impl Election for Lease {
+    fn leader(&self) -> Option<&str> {
+        let (holder, expires) = self.holder.as_ref()?;
+        let margin = if *holder == self.me {
+            self.margin.as_millis() as u64
+        } else {
+            0
+        };
+        (expires.saturating_sub(margin) > now_ms()).then_some(holder.as_str())
+    }
+
+    fn is_leader(&self) -> bool {
+        self.leader() == Some(self.me.as_str())
+    }
+
+    fn take_changes(&mut self) -> Vec<LeaderChanged> {
+        std::mem::take(&mut self.changes)
+    }
+}
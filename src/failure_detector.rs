@@ -0,0 +1,185 @@
+//! Phi-accrual failure detector.
+//!
+//! Nodes send each other periodic heartbeats (any message counts), and for
+//! every peer we keep a window of heartbeat inter-arrival times. `phi` is how
+//! unlikely the current silence is under a normal distribution fitted to that
+//! window, on a log10 scale: phi 1 means roughly a 10% chance the peer is
+//! still fine, phi 8 about 0.000001%. A peer is suspected once phi crosses the
+//! threshold, which tells a slow peer (wide distribution) from a dead one.
+//!
+//! Heartbeats to every peer every tick cost more messages than the
+//! workloads' budgets allow, so detection is opt-in: set
+//! `RUSTENGAN_FAILURE_DETECTOR=1`. Without it nodes send no heartbeats and
+//! treat every peer as alive.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+pub const DETECTOR_ENV: &str = "RUSTENGAN_FAILURE_DETECTOR";
+
+/// How often nodes should send heartbeats to the peers they care about.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub threshold: f64,
+    /// Number of inter-arrival samples kept per peer.
+    pub window: usize,
+    /// Floor for the standard deviation so a very regular peer is not
+    /// suspected over a few milliseconds of jitter.
+    pub min_std_dev: Duration,
+    /// Extra silence tolerated on top of the mean, e.g. for GC pauses.
+    pub acceptable_pause: Duration,
+    /// Assumed interval until real samples arrive.
+    pub first_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            threshold: 8.0,
+            window: 100,
+            min_std_dev: Duration::from_millis(50),
+            acceptable_pause: Duration::from_millis(200),
+            first_interval: HEARTBEAT_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Suspicion {
+    Suspected(String),
+    Recovered(String),
+}
+
+#[derive(Debug)]
+struct History {
+    last: Instant,
+    intervals: VecDeque<f64>,
+}
+
+#[derive(Debug)]
+pub struct FailureDetector {
+    /// Off, nobody is ever suspected and nobody needs heartbeats.
+    enabled: bool,
+    config: Config,
+    peers: HashMap<String, History>,
+    suspected: HashSet<String>,
+}
+
+impl FailureDetector {
+    pub fn new(config: Config) -> Self {
+        FailureDetector {
+            enabled: true,
+            config,
+            peers: HashMap::new(),
+            suspected: HashSet::new(),
+        }
+    }
+
+    /// A detector that never suspects anyone.
+    pub fn disabled() -> Self {
+        FailureDetector {
+            enabled: false,
+            ..FailureDetector::default()
+        }
+    }
+
+    /// On if `RUSTENGAN_FAILURE_DETECTOR` is set to anything but `0`.
+    pub fn from_env() -> Self {
+        match std::env::var(DETECTOR_ENV) {
+            Ok(v) if v != "0" => FailureDetector::default(),
+            _ => FailureDetector::disabled(),
+        }
+    }
+
+    /// Whether the node should send heartbeats for this detector to go on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Record a sign of life from `peer`.
+    pub fn heartbeat(&mut self, peer: &str) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        match self.peers.get_mut(peer) {
+            Some(history) => {
+                let interval = now.duration_since(history.last).as_secs_f64() * 1000.0;
+                history.last = now;
+                if history.intervals.len() >= self.config.window {
+                    history.intervals.pop_front();
+                }
+                history.intervals.push_back(interval);
+            }
+            None => {
+                let first = self.config.first_interval.as_secs_f64() * 1000.0;
+                self.peers.insert(
+                    peer.to_string(),
+                    History {
+                        last: now,
+                        // Two samples around the expected interval give a
+                        // sane mean and deviation to start from.
+                        intervals: VecDeque::from([first * 0.75, first * 1.25]),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Suspicion level for `peer`. Peers we never heard from are at 0.
+    pub fn phi(&self, peer: &str) -> f64 {
+        let Some(history) = self.peers.get(peer) else {
+            return 0.0;
+        };
+        let n = history.intervals.len() as f64;
+        let mean = history.intervals.iter().sum::<f64>() / n;
+        let variance = history
+            .intervals
+            .iter()
+            .map(|i| (i - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        let min_std_dev = self.config.min_std_dev.as_secs_f64() * 1000.0;
+        let std_dev = variance.sqrt().max(min_std_dev);
+        let mean = mean + self.config.acceptable_pause.as_secs_f64() * 1000.0;
+        let elapsed = history.last.elapsed().as_secs_f64() * 1000.0;
+        // Logistic approximation of the normal CDF, as in Akka.
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        }
+    }
+
+    pub fn is_alive(&self, peer: &str) -> bool {
+        !self.enabled || self.phi(peer) < self.config.threshold
+    }
+
+    /// Peers that crossed the threshold in either direction since the last
+    /// call.
+    pub fn poll(&mut self) -> Vec<Suspicion> {
+        let mut events = Vec::new();
+        let peers: Vec<String> = self.peers.keys().cloned().collect();
+        for peer in peers {
+            let alive = self.is_alive(&peer);
+            if !alive && self.suspected.insert(peer.clone()) {
+                events.push(Suspicion::Suspected(peer));
+            } else if alive && self.suspected.remove(&peer) {
+                events.push(Suspicion::Recovered(peer));
+            }
+        }
+        events
+    }
+}
+
+impl Default for FailureDetector {
+    fn default() -> Self {
+        FailureDetector::new(Config::default())
+    }
+}
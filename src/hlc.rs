@@ -0,0 +1,72 @@
+//! Hybrid logical clocks.
+//!
+//! Timestamps follow physical time where clocks agree, but never go
+//! backwards and always order after any timestamp the clock has seen, so
+//! they respect causality across nodes whose clocks drift.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp {
+    /// Milliseconds since the Unix epoch.
+    pub wall: u64,
+    /// Breaks ties between timestamps with the same `wall`.
+    pub logical: u64,
+}
+
+/// A timestamp made unique by the node that issued it, e.g. to version
+/// writes for last-writer-wins. Ordered by time, then node id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Version {
+    pub time: Timestamp,
+    pub node: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Clock {
+    last: Timestamp,
+}
+
+impl Clock {
+    /// Timestamp for a local event.
+    pub fn now(&mut self) -> Timestamp {
+        let physical = physical_now();
+        if physical > self.last.wall {
+            self.last = Timestamp {
+                wall: physical,
+                logical: 0,
+            };
+        } else {
+            self.last.logical += 1;
+        }
+        self.last
+    }
+
+    /// Advance past a timestamp received from another node.
+    pub fn observe(&mut self, remote: Timestamp) -> Timestamp {
+        let physical = physical_now();
+        let wall = physical.max(self.last.wall).max(remote.wall);
+        let logical = if wall == self.last.wall && wall == remote.wall {
+            self.last.logical.max(remote.logical) + 1
+        } else if wall == self.last.wall {
+            self.last.logical + 1
+        } else if wall == remote.wall {
+            remote.logical + 1
+        } else {
+            0
+        };
+        self.last = Timestamp { wall, logical };
+        self.last
+    }
+}
+
+fn physical_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
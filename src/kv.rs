@@ -0,0 +1,172 @@
+//! Messages understood by maelstrom's key-value services, and a replicated
+//! store that speaks them.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::consensus::StateMachine;
+
+pub const LIN_KV: &str = "lin-kv";
+pub const SEQ_KV: &str = "seq-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+/// Indefinite: the operation may or may not have taken effect.
+pub const TIMEOUT: isize = 0;
+pub const NOT_SUPPORTED: isize = 10;
+pub const TEMPORARILY_UNAVAILABLE: isize = 11;
+pub const MALFORMED_REQUEST: isize = 12;
+/// Indefinite: the node hit an error it cannot tell the outcome through.
+pub const CRASH: isize = 13;
+pub const KEY_DOES_NOT_EXIST: isize = 20;
+pub const PRECONDITION_FAILED: isize = 22;
+pub const TXN_CONFLICT: isize = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, rename = "create_if_not_exists")]
+        put: bool,
+    },
+    CasOk,
+    /// Transaction of `["r", key, null]` and `["w", key, value]` operations,
+    /// applied atomically as in the `txn-rw-register` workload.
+    Txn {
+        txn: Vec<(String, Value, Value)>,
+    },
+    TxnOk {
+        txn: Vec<(String, Value, Value)>,
+    },
+    /// Append to the log under `key`, as for the kafka-style workload.
+    Append {
+        key: Value,
+        value: Value,
+    },
+    AppendOk {
+        offset: usize,
+    },
+    /// Log entries under `key` starting at offset `from`.
+    ReadLog {
+        key: Value,
+        from: usize,
+    },
+    ReadLogOk {
+        entries: Vec<(usize, Value)>,
+    },
+    Error {
+        code: isize,
+        text: String,
+    },
+}
+
+/// In-memory key-value store with maelstrom's `lin-kv` semantics, plus
+/// transactions for ta-map and append-only logs for k-log, to run behind a
+/// [`crate::consensus::Consensus`] implementation. Commands and outputs are
+/// the request and reply payloads themselves.
+#[derive(Debug, Default)]
+pub struct Store {
+    /// Keyed by the key's JSON text, since maelstrom keys can be any value.
+    values: HashMap<String, Value>,
+    logs: HashMap<String, Vec<Value>>,
+}
+
+impl Store {
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.values.get(&key.to_string())
+    }
+}
+
+impl StateMachine for Store {
+    type Command = Payload;
+    type Output = Payload;
+
+    fn apply(&mut self, command: &Payload) -> Payload {
+        match command {
+            Payload::Read { key } => match self.get(key) {
+                Some(value) => Payload::ReadOk {
+                    value: value.clone(),
+                },
+                None => Payload::Error {
+                    code: KEY_DOES_NOT_EXIST,
+                    text: "not found".to_string(),
+                },
+            },
+            Payload::Write { key, value } => {
+                self.values.insert(key.to_string(), value.clone());
+                Payload::WriteOk
+            }
+            Payload::Cas { key, from, to, put } => match self.values.get(&key.to_string()) {
+                None if !put => Payload::Error {
+                    code: KEY_DOES_NOT_EXIST,
+                    text: "not found".to_string(),
+                },
+                Some(current) if current != from => Payload::Error {
+                    code: PRECONDITION_FAILED,
+                    text: format!("current value {current} is not {from}"),
+                },
+                _ => {
+                    self.values.insert(key.to_string(), to.clone());
+                    Payload::CasOk
+                }
+            },
+            Payload::Txn { txn } if txn.iter().all(|(op, ..)| op == "r" || op == "w") => {
+                let txn = txn
+                    .iter()
+                    .map(|(op, key, value)| match op.as_str() {
+                        "r" => {
+                            let current = self.get(key).cloned().unwrap_or(Value::Null);
+                            (op.clone(), key.clone(), current)
+                        }
+                        _ => {
+                            self.values.insert(key.to_string(), value.clone());
+                            (op.clone(), key.clone(), value.clone())
+                        }
+                    })
+                    .collect();
+                Payload::TxnOk { txn }
+            }
+            Payload::Append { key, value } => {
+                let log = self.logs.entry(key.to_string()).or_default();
+                log.push(value.clone());
+                Payload::AppendOk {
+                    offset: log.len() - 1,
+                }
+            }
+            Payload::ReadLog { key, from } => {
+                let entries = self
+                    .logs
+                    .get(&key.to_string())
+                    .map(|log| {
+                        log.iter()
+                            .enumerate()
+                            .skip(*from)
+                            .map(|(offset, value)| (offset, value.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Payload::ReadLogOk { entries }
+            }
+            _ => Payload::Error {
+                code: NOT_SUPPORTED,
+                text: "not supported".to_string(),
+            },
+        }
+    }
+}
@@ -1,4 +1,27 @@
-use std::io::{StdoutLock, Write};
+pub mod broadcast;
+pub mod checker;
+pub mod cluster;
+pub mod consensus;
+pub mod digest;
+pub mod election;
+pub mod failure_detector;
+pub mod hlc;
+pub mod kv;
+pub mod membership;
+pub mod mvcc;
+pub mod paxos;
+pub mod raft;
+pub mod retry;
+pub mod rng;
+pub mod segment;
+pub mod snapshot;
+pub mod storage;
+pub mod topology;
+
+use std::{
+    cmp::Ordering,
+    io::{StdoutLock, Write},
+};
 
 use anyhow::Context;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
@@ -41,6 +64,28 @@ where
     }
 }
 
+impl<Payload> Message<Payload> {
+    /// Re-wrap the payload, e.g. a library component's message into the
+    /// node's own payload enum.
+    pub fn map<P>(self, f: impl FnOnce(Payload) -> P) -> Message<P> {
+        Message {
+            src: self.src,
+            dest: self.dest,
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                payload: f(self.body.payload),
+            },
+        }
+    }
+}
+
+/// maelstrom names nodes `n0..nN`; compare them numerically rather than
+/// lexically so `n10` sorts after `n9`.
+pub fn cmp_node_ids(a: &str, b: &str) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Body<Payload> {
     #[serde(rename = "msg_id")]
@@ -0,0 +1,368 @@
+//! HyParView partial membership.
+//!
+//! Each node keeps a small symmetric *active* view that dissemination runs
+//! over, and a larger *passive* view of backup peers. Joins travel as random
+//! walks, passive views are refreshed by periodic shuffles, and an active
+//! peer reported through [`HyParView::peer_failed`] (normally by the failure
+//! detector) is replaced by promoting someone from the passive view.
+//!
+//! The protocol is sans-IO: every call returns the messages to send as
+//! `(dest, Payload)` pairs and the node wraps them into its own payload type.
+
+use std::{
+    collections::BTreeSet,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::rng::Rng;
+
+pub const MEMBERSHIP_ENV: &str = "RUSTENGAN_MEMBERSHIP";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Join,
+    ForwardJoin {
+        node: String,
+        ttl: usize,
+    },
+    Neighbor {
+        high_priority: bool,
+    },
+    NeighborOk {
+        accepted: bool,
+    },
+    Disconnect,
+    Shuffle {
+        origin: String,
+        nodes: Vec<String>,
+        ttl: usize,
+    },
+    ShuffleReply {
+        nodes: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Up(String),
+    Down(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub active_size: usize,
+    pub passive_size: usize,
+    /// Active random walk length for joins.
+    pub arwl: usize,
+    /// At which remaining ttl a forwarded join also lands in the passive view.
+    pub prwl: usize,
+    pub shuffle_active: usize,
+    pub shuffle_passive: usize,
+    pub shuffle_ttl: usize,
+    /// Shuffle every this many ticks.
+    pub shuffle_every: usize,
+    /// How long to wait for an answer to a neighbor request.
+    pub neighbor_timeout: Duration,
+}
+
+impl Config {
+    /// The paper's defaults scaled to the cluster size: an active view of
+    /// `log2(n) + 1` and a passive view six times that.
+    pub fn for_cluster(nodes: usize) -> Self {
+        let active_size = (usize::BITS - nodes.max(1).leading_zeros()) as usize + 1;
+        Config {
+            active_size,
+            passive_size: active_size * 6,
+            arwl: 6,
+            prwl: 3,
+            shuffle_active: 3,
+            shuffle_passive: 4,
+            shuffle_ttl: 3,
+            shuffle_every: 10,
+            neighbor_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Whether nodes should run HyParView instead of assuming the static
+/// `node_ids` list is a full mesh. Set `RUSTENGAN_MEMBERSHIP=hyparview`.
+pub fn enabled() -> bool {
+    std::env::var(MEMBERSHIP_ENV).is_ok_and(|v| v == "hyparview")
+}
+
+pub struct HyParView {
+    me: String,
+    config: Config,
+    active: BTreeSet<String>,
+    passive: BTreeSet<String>,
+    /// Passive peer we asked to become a neighbour and have not heard back from.
+    pending: Option<(String, Instant)>,
+    changes: Vec<Change>,
+    ticks: usize,
+    rng: Rng,
+}
+
+impl HyParView {
+    /// The passive view starts as a random sample of `node_ids`, so a node can
+    /// repair its active view even before any shuffle has happened.
+    pub fn new(me: &str, node_ids: &[String], config: Config) -> Self {
+        let mut rng = Rng::for_node(me);
+        let mut others: Vec<String> = node_ids.iter().filter(|n| *n != me).cloned().collect();
+        rng.shuffle(&mut others);
+        HyParView {
+            me: me.to_string(),
+            passive: others.into_iter().take(config.passive_size).collect(),
+            config,
+            active: BTreeSet::new(),
+            pending: None,
+            changes: Vec::new(),
+            ticks: 0,
+            rng,
+        }
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = &String> {
+        self.active.iter()
+    }
+
+    pub fn passive(&self) -> impl Iterator<Item = &String> {
+        self.passive.iter()
+    }
+
+    pub fn is_active(&self, peer: &str) -> bool {
+        self.active.contains(peer)
+    }
+
+    /// Active view changes since the last call.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Join through `contact`, usually the first node in the cluster.
+    pub fn join(&mut self, contact: &str) -> Vec<(String, Payload)> {
+        let mut out = Vec::new();
+        if contact == self.me {
+            return out;
+        }
+        self.add_active(contact, &mut out);
+        out.push((contact.to_string(), Payload::Join));
+        out
+    }
+
+    pub fn handle(&mut self, src: &str, payload: &Payload) -> Vec<(String, Payload)> {
+        let mut out = Vec::new();
+        match payload {
+            Payload::Join => {
+                self.add_active(src, &mut out);
+                for peer in self.active.iter().filter(|p| *p != src) {
+                    out.push((
+                        peer.clone(),
+                        Payload::ForwardJoin {
+                            node: src.to_string(),
+                            ttl: self.config.arwl,
+                        },
+                    ));
+                }
+            }
+            Payload::ForwardJoin { node, ttl } => {
+                if node == &self.me {
+                    return out;
+                }
+                if *ttl == 0 || self.active.len() <= 1 {
+                    if !self.active.contains(node) {
+                        self.add_active(node, &mut out);
+                        out.push((
+                            node.clone(),
+                            Payload::Neighbor {
+                                high_priority: true,
+                            },
+                        ));
+                    }
+                    return out;
+                }
+                if *ttl == self.config.prwl {
+                    self.add_passive(node);
+                }
+                match self.random_active(&[src, node]) {
+                    Some(next) => out.push((
+                        next,
+                        Payload::ForwardJoin {
+                            node: node.clone(),
+                            ttl: ttl - 1,
+                        },
+                    )),
+                    None => {
+                        self.add_active(node, &mut out);
+                        out.push((
+                            node.clone(),
+                            Payload::Neighbor {
+                                high_priority: true,
+                            },
+                        ));
+                    }
+                }
+            }
+            Payload::Neighbor { high_priority } => {
+                let accepted = *high_priority
+                    || self.active.contains(src)
+                    || self.active.len() < self.config.active_size;
+                if accepted {
+                    self.add_active(src, &mut out);
+                }
+                out.push((src.to_string(), Payload::NeighborOk { accepted }));
+            }
+            Payload::NeighborOk { accepted } => {
+                if self.pending.as_ref().is_some_and(|(p, _)| p == src) {
+                    self.pending = None;
+                }
+                if *accepted {
+                    self.add_active(src, &mut out);
+                }
+            }
+            Payload::Disconnect => {
+                if self.active.remove(src) {
+                    self.changes.push(Change::Down(src.to_string()));
+                    self.add_passive(src);
+                }
+            }
+            Payload::Shuffle { origin, nodes, ttl } => {
+                if origin == &self.me {
+                    return out;
+                }
+                if *ttl > 0 && self.active.len() > 1 {
+                    if let Some(next) = self.random_active(&[src, origin]) {
+                        out.push((
+                            next,
+                            Payload::Shuffle {
+                                origin: origin.clone(),
+                                nodes: nodes.clone(),
+                                ttl: ttl - 1,
+                            },
+                        ));
+                        return out;
+                    }
+                }
+                let mut reply: Vec<String> = self.passive.iter().cloned().collect();
+                self.rng.shuffle(&mut reply);
+                reply.truncate(nodes.len());
+                out.push((origin.clone(), Payload::ShuffleReply { nodes: reply }));
+                for node in nodes.iter().chain([origin]) {
+                    self.add_passive(node);
+                }
+            }
+            Payload::ShuffleReply { nodes } => {
+                for node in nodes {
+                    self.add_passive(node);
+                }
+            }
+        }
+        out
+    }
+
+    /// Periodic work: refilling the active view from the passive one and
+    /// shuffles.
+    pub fn tick(&mut self) -> Vec<(String, Payload)> {
+        self.ticks += 1;
+        let mut out = Vec::new();
+        let now = Instant::now();
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|(_, since)| now.duration_since(*since) > self.config.neighbor_timeout)
+        {
+            // Asked a dead passive peer; forget it and try another.
+            let (peer, _) = self.pending.take().expect("pending checked above");
+            self.passive.remove(&peer);
+        }
+        self.refill(&mut out);
+        if self.ticks.is_multiple_of(self.config.shuffle_every) {
+            if let Some(peer) = self.random_active(&[]) {
+                let mut active: Vec<String> = self.active.iter().cloned().collect();
+                let mut passive: Vec<String> = self.passive.iter().cloned().collect();
+                self.rng.shuffle(&mut active);
+                self.rng.shuffle(&mut passive);
+                let nodes = active
+                    .into_iter()
+                    .take(self.config.shuffle_active)
+                    .chain(passive.into_iter().take(self.config.shuffle_passive))
+                    .collect();
+                out.push((
+                    peer,
+                    Payload::Shuffle {
+                        origin: self.me.clone(),
+                        nodes,
+                        ttl: self.config.shuffle_ttl,
+                    },
+                ));
+            }
+        }
+        out
+    }
+
+    /// Drop `peer` from the active view and start replacing it.
+    pub fn peer_failed(&mut self, peer: &str) -> Vec<(String, Payload)> {
+        let mut out = Vec::new();
+        if self.active.remove(peer) {
+            self.changes.push(Change::Down(peer.to_string()));
+        }
+        self.passive.remove(peer);
+        self.refill(&mut out);
+        out
+    }
+
+    fn refill(&mut self, out: &mut Vec<(String, Payload)>) {
+        if self.active.len() >= self.config.active_size || self.pending.is_some() {
+            return;
+        }
+        let candidates: Vec<String> = self.passive.iter().cloned().collect();
+        if let Some(peer) = self.rng.choose(&candidates).cloned() {
+            let high_priority = self.active.is_empty();
+            self.pending = Some((peer.clone(), Instant::now()));
+            out.push((peer, Payload::Neighbor { high_priority }));
+        }
+    }
+
+    fn add_active(&mut self, peer: &str, out: &mut Vec<(String, Payload)>) {
+        if peer == self.me || self.active.contains(peer) {
+            return;
+        }
+        if self.active.len() >= self.config.active_size {
+            if let Some(evicted) = self.random_active(&[]) {
+                self.active.remove(&evicted);
+                self.changes.push(Change::Down(evicted.clone()));
+                self.add_passive(&evicted);
+                out.push((evicted, Payload::Disconnect));
+            }
+        }
+        self.passive.remove(peer);
+        self.active.insert(peer.to_string());
+        self.changes.push(Change::Up(peer.to_string()));
+    }
+
+    fn add_passive(&mut self, peer: &str) {
+        if peer == self.me || self.active.contains(peer) || self.passive.contains(peer) {
+            return;
+        }
+        if self.passive.len() >= self.config.passive_size {
+            let passive: Vec<String> = self.passive.iter().cloned().collect();
+            if let Some(dropped) = self.rng.choose(&passive).cloned() {
+                self.passive.remove(&dropped);
+            }
+        }
+        self.passive.insert(peer.to_string());
+    }
+
+    fn random_active(&mut self, except: &[&str]) -> Option<String> {
+        let candidates: Vec<String> = self
+            .active
+            .iter()
+            .filter(|p| !except.contains(&p.as_str()))
+            .cloned()
+            .collect();
+        self.rng.choose(&candidates).cloned()
+    }
+}
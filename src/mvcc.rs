@@ -0,0 +1,168 @@
+//! Multi-version key-value store.
+//!
+//! Every committed write is kept as a separate version of its key, ordered
+//! by commit timestamp. A transaction reads from a snapshot, the newest
+//! version of each key at or below its start timestamp, so it sees a
+//! consistent state however many commits land while it runs. At commit, a
+//! key it writes that gained a version after its snapshot is a write-write
+//! conflict (first committer wins). Versions no running snapshot can see
+//! any more are garbage-collected.
+//!
+//! A version need not be a whole value: it can also be a change to the one
+//! before it, like a list append, so concurrent changes all survive. Once
+//! no older version can arrive any more, changes are collapsed into a whole
+//! value so their history can be collected too.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    ops::Bound,
+};
+
+/// What a version of a key holds.
+pub trait Versioned {
+    /// Whether this is a whole value, readable without older versions.
+    fn is_whole(&self) -> bool;
+}
+
+#[derive(Debug)]
+pub struct Store<K, V, T> {
+    versions: HashMap<K, BTreeMap<T, V>>,
+    /// Start timestamps of running transactions, with how many started at
+    /// each.
+    active: BTreeMap<T, usize>,
+}
+
+impl<K, V, T> Default for Store<K, V, T> {
+    fn default() -> Self {
+        Store {
+            versions: HashMap::new(),
+            active: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: Versioned, T: Ord + Clone> Store<K, V, T> {
+    /// Start a transaction reading at `snapshot`. Versions it can see are
+    /// kept until [`Store::end`].
+    pub fn begin(&mut self, snapshot: T) -> T {
+        *self.active.entry(snapshot.clone()).or_default() += 1;
+        snapshot
+    }
+
+    pub fn end(&mut self, snapshot: &T) {
+        if let Some(count) = self.active.get_mut(snapshot) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(snapshot);
+            }
+        }
+    }
+
+    /// The versions of `key` that make up its value as of `snapshot`,
+    /// oldest first: the newest whole one and every change after it.
+    pub fn read<'a>(&'a self, key: &K, snapshot: &'a T) -> impl Iterator<Item = &'a V> + 'a {
+        let versions = self.versions.get(key);
+        let start = versions.and_then(|versions| last_whole(versions.range(..=snapshot)));
+        versions
+            .into_iter()
+            .flat_map(move |versions| {
+                let from = start.map_or(Bound::Unbounded, Bound::Included);
+                versions.range((from, Bound::Included(snapshot)))
+            })
+            .map(|(_, value)| value)
+    }
+
+    /// Every version of every key, e.g. to snapshot the store.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &T, &V)> {
+        self.versions.iter().flat_map(|(key, versions)| {
+            versions
+                .iter()
+                .map(move |(version, value)| (key, version, value))
+        })
+    }
+
+    /// The newest version of `key`.
+    pub fn latest(&self, key: &K) -> Option<(&T, &V)> {
+        self.versions.get(key)?.last_key_value()
+    }
+
+    /// The first of `keys` written by a commit after `snapshot`.
+    pub fn conflict<'a>(&self, snapshot: &T, keys: impl IntoIterator<Item = &'a K>) -> Option<&'a K>
+    where
+        K: 'a,
+    {
+        keys.into_iter().find(|key| {
+            self.latest(key)
+                .is_some_and(|(version, _)| version > snapshot)
+        })
+    }
+
+    /// Install a committed version. Versions arrive in any order, and the
+    /// same one arriving twice keeps the first, unless the second is whole:
+    /// that is the first collapsed with everything before it.
+    pub fn write(&mut self, key: K, version: T, value: V) {
+        let versions = self.versions.entry(key).or_default();
+        match versions.get(&version) {
+            Some(current) if current.is_whole() || !value.is_whole() => {}
+            _ => {
+                versions.insert(version, value);
+            }
+        }
+    }
+
+    /// Fold each key's versions below `horizon` into a whole one at the
+    /// newest of them, so [`Store::gc`] can drop the rest. Only safe once
+    /// no version below `horizon` can still arrive. `fold` turns the
+    /// versions making up a value, oldest first, into a whole one. Running
+    /// snapshots hold the horizon back, since they still read the changes.
+    pub fn collapse(&mut self, horizon: &T, fold: impl Fn(&[&V]) -> Option<V>) {
+        let horizon = match self.active.first_key_value() {
+            Some((oldest, _)) if oldest < horizon => oldest,
+            _ => horizon,
+        };
+        for versions in self.versions.values_mut() {
+            let below = versions.range(..horizon);
+            let Some((newest, value)) = below.clone().next_back() else {
+                continue;
+            };
+            if value.is_whole() {
+                continue;
+            }
+            let newest = newest.clone();
+            let from = last_whole(below).map_or(Bound::Unbounded, |t| Bound::Included(t.clone()));
+            let changes: Vec<&V> = versions
+                .range((from, Bound::Included(newest.clone())))
+                .map(|(_, value)| value)
+                .collect();
+            if let Some(whole) = fold(&changes) {
+                versions.insert(newest, whole);
+            }
+        }
+    }
+
+    /// Drop versions the oldest running snapshot no longer reads: anything
+    /// before its newest whole version. With nothing running, everything
+    /// before each key's newest whole version goes.
+    pub fn gc(&mut self) {
+        let horizon = self.active.first_key_value().map(|(t, _)| t.clone());
+        for versions in self.versions.values_mut() {
+            let keep = match &horizon {
+                Some(horizon) => last_whole(versions.range(..=horizon)),
+                None => last_whole(versions.iter()),
+            };
+            if let Some(keep) = keep.cloned() {
+                *versions = versions.split_off(&keep);
+            }
+        }
+    }
+}
+
+fn last_whole<'a, T: 'a, V: Versioned + 'a>(
+    versions: impl DoubleEndedIterator<Item = (&'a T, &'a V)>,
+) -> Option<&'a T> {
+    versions
+        .rev()
+        .find(|(_, value)| value.is_whole())
+        .map(|(version, _)| version)
+}
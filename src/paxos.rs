@@ -0,0 +1,533 @@
+//! Multi-Paxos.
+//!
+//! A distinguished proposer runs phase 1 once for all future slots with a
+//! ballot higher than any it has seen, re-proposes whatever a majority of
+//! acceptors had already accepted (filling gaps with no-ops), and from then on
+//! only runs phase 2 per slot. Chosen values are announced to every node;
+//! heartbeats carry how many slots are chosen so a node that missed some can
+//! ask for them. A node that stops hearing from the leader starts phase 1
+//! itself, and anyone told about a higher ballot steps down.
+//!
+//! Every node is proposer, acceptor and learner. Unanswered `accept`s are
+//! retransmitted through [`InFlight`], with the rpc id standing in for the
+//! `msg_id` the library's nodes use.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consensus::{Consensus, Proposal, StateMachine},
+    retry::InFlight,
+    rng::Rng,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Ballot {
+    pub round: usize,
+    pub node: String,
+}
+
+/// `None` is the no-op a new leader fills holes in the log with.
+pub type Value<C> = Option<Proposal<C>>;
+
+/// What an acceptor reports in a promise: slot, ballot it accepted in, value.
+pub type Accepted<C> = (usize, Ballot, Value<C>);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload<C> {
+    Prepare {
+        ballot: Ballot,
+        /// Slots below this are already chosen at the proposer.
+        from_slot: usize,
+    },
+    Promise {
+        ballot: Ballot,
+        accepted: Vec<Accepted<C>>,
+    },
+    Accept {
+        ballot: Ballot,
+        slot: usize,
+        value: Value<C>,
+        rpc: usize,
+    },
+    Accepted {
+        ballot: Ballot,
+        slot: usize,
+        rpc: usize,
+    },
+    /// Refusal of a `prepare` or `accept`, carrying the ballot we promised.
+    Nack {
+        promised: Ballot,
+    },
+    Chosen {
+        slot: usize,
+        value: Value<C>,
+    },
+    Heartbeat {
+        ballot: Ballot,
+        chosen: usize,
+    },
+    Catchup {
+        from_slot: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// A follower starts phase 1 after hearing nothing from the leader for a
+    /// timeout drawn from this range.
+    pub leader_timeout: (Duration, Duration),
+    pub heartbeat: Duration,
+    /// Resend an unanswered `accept` after this long.
+    pub accept_timeout: Duration,
+    /// Most `accept`s awaiting an answer; the oldest stop being resent.
+    pub in_flight_capacity: usize,
+    /// Most chosen values sent in answer to one `catchup`.
+    pub max_batch: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            leader_timeout: (Duration::from_millis(300), Duration::from_millis(600)),
+            heartbeat: Duration::from_millis(100),
+            accept_timeout: Duration::from_millis(200),
+            in_flight_capacity: 4096,
+            max_batch: 64,
+        }
+    }
+}
+
+pub struct Paxos<S: StateMachine> {
+    me: String,
+    peers: Vec<String>,
+    config: Config,
+    rng: Rng,
+    // Acceptor
+    promised: Ballot,
+    accepted: BTreeMap<usize, (Ballot, Value<S::Command>)>,
+    // Proposer
+    ballot: Ballot,
+    leading: bool,
+    /// Promises collected while in phase 1.
+    promises: Option<HashMap<String, Vec<Accepted<S::Command>>>>,
+    next_slot: usize,
+    /// Slots in phase 2 with the nodes that accepted them.
+    proposals: HashMap<usize, (Value<S::Command>, HashSet<String>)>,
+    in_flight: InFlight<usize>,
+    rpc: usize,
+    // Learner
+    chosen: BTreeMap<usize, Value<S::Command>>,
+    /// Applied values by slot, to answer `catchup`s with.
+    decided: Vec<Value<S::Command>>,
+    /// First slot not applied yet; everything below is chosen.
+    next_apply: usize,
+    leader: Option<String>,
+    leader_deadline: Instant,
+    last_heartbeat: Instant,
+    state_machine: S,
+    applied: Vec<(Proposal<S::Command>, S::Output)>,
+}
+
+impl<S: StateMachine> Paxos<S> {
+    pub fn new(me: &str, node_ids: &[String], state_machine: S, config: Config) -> Self {
+        let mut paxos = Paxos {
+            me: me.to_string(),
+            peers: node_ids.iter().filter(|n| *n != me).cloned().collect(),
+            in_flight: InFlight::new(config.accept_timeout, usize::MAX, config.in_flight_capacity),
+            config,
+            rng: Rng::for_node(me),
+            promised: Ballot::default(),
+            accepted: BTreeMap::new(),
+            ballot: Ballot::default(),
+            leading: false,
+            promises: None,
+            next_slot: 0,
+            proposals: HashMap::new(),
+            rpc: 0,
+            chosen: BTreeMap::new(),
+            decided: Vec::new(),
+            next_apply: 0,
+            leader: None,
+            leader_deadline: Instant::now(),
+            last_heartbeat: Instant::now(),
+            state_machine,
+            applied: Vec::new(),
+        };
+        paxos.reset_leader_deadline();
+        paxos
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leading
+    }
+
+    fn majority(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn reset_leader_deadline(&mut self) {
+        let (min, max) = self.config.leader_timeout;
+        let spread = max.saturating_sub(min).as_millis() as usize;
+        let jitter = Duration::from_millis(self.rng.below(spread.max(1)) as u64);
+        self.leader_deadline = Instant::now() + min + jitter;
+    }
+
+    fn step_down(&mut self, ballot: &Ballot) {
+        if *ballot > self.ballot {
+            self.ballot.round = ballot.round;
+        }
+        self.leading = false;
+        self.promises = None;
+        self.proposals.clear();
+        self.in_flight.retain(|_| false);
+        if ballot.node != self.me {
+            self.leader = Some(ballot.node.clone());
+        }
+        self.reset_leader_deadline();
+    }
+
+    fn accepted_from(&self, from_slot: usize) -> Vec<Accepted<S::Command>> {
+        self.accepted
+            .range(from_slot..)
+            .map(|(slot, (ballot, value))| (*slot, ballot.clone(), value.clone()))
+            .collect()
+    }
+
+    fn prepare(&mut self) -> Vec<(String, Payload<S::Command>)> {
+        self.ballot = Ballot {
+            round: self.ballot.round.max(self.promised.round) + 1,
+            node: self.me.clone(),
+        };
+        self.promised = self.ballot.clone();
+        self.leading = false;
+        self.leader = None;
+        self.promises = Some(HashMap::from([(
+            self.me.clone(),
+            self.accepted_from(self.next_apply),
+        )]));
+        self.reset_leader_deadline();
+        let mut out: Vec<_> = self
+            .peers
+            .iter()
+            .map(|p| {
+                (
+                    p.clone(),
+                    Payload::Prepare {
+                        ballot: self.ballot.clone(),
+                        from_slot: self.next_apply,
+                    },
+                )
+            })
+            .collect();
+        out.extend(self.check_promises());
+        out
+    }
+
+    /// Become leader once a majority promised, re-proposing what they had
+    /// accepted.
+    fn check_promises(&mut self) -> Vec<(String, Payload<S::Command>)> {
+        if self
+            .promises
+            .as_ref()
+            .is_none_or(|p| p.len() < self.majority())
+        {
+            return Vec::new();
+        }
+        let promises = self.promises.take().expect("checked above");
+        let mut merged: BTreeMap<usize, (Ballot, Value<S::Command>)> = BTreeMap::new();
+        for (slot, ballot, value) in promises.into_values().flatten() {
+            if merged.get(&slot).is_none_or(|(b, _)| ballot > *b) {
+                merged.insert(slot, (ballot, value));
+            }
+        }
+        self.leading = true;
+        self.leader = Some(self.me.clone());
+        self.next_slot = merged
+            .keys()
+            .next_back()
+            .map_or(self.next_apply, |s| s + 1)
+            .max(self.next_apply);
+        let mut out = Vec::new();
+        for slot in self.next_apply..self.next_slot {
+            if self.chosen.contains_key(&slot) {
+                continue;
+            }
+            let value = merged.remove(&slot).and_then(|(_, v)| v);
+            out.extend(self.accept(slot, value));
+        }
+        out.extend(self.heartbeat());
+        out
+    }
+
+    /// Phase 2 for `slot`, accepting locally first.
+    fn accept(
+        &mut self,
+        slot: usize,
+        value: Value<S::Command>,
+    ) -> Vec<(String, Payload<S::Command>)> {
+        self.accepted
+            .insert(slot, (self.ballot.clone(), value.clone()));
+        self.proposals
+            .insert(slot, (value.clone(), HashSet::from([self.me.clone()])));
+        let mut out = Vec::new();
+        for peer in self.peers.clone() {
+            out.push((peer.clone(), self.accept_to(&peer, slot, value.clone())));
+        }
+        out.extend(self.check_accepted(slot));
+        out
+    }
+
+    fn accept_to(
+        &mut self,
+        peer: &str,
+        slot: usize,
+        value: Value<S::Command>,
+    ) -> Payload<S::Command> {
+        self.rpc += 1;
+        self.in_flight.track(self.rpc, peer.to_string(), slot);
+        Payload::Accept {
+            ballot: self.ballot.clone(),
+            slot,
+            value,
+            rpc: self.rpc,
+        }
+    }
+
+    fn check_accepted(&mut self, slot: usize) -> Vec<(String, Payload<S::Command>)> {
+        let Some((value, acks)) = self.proposals.get(&slot) else {
+            return Vec::new();
+        };
+        if acks.len() < self.majority() {
+            return Vec::new();
+        }
+        let value = value.clone();
+        self.proposals.remove(&slot);
+        self.in_flight.retain(|e| e.data != slot);
+        self.learn(slot, value.clone());
+        self.peers
+            .iter()
+            .map(|p| {
+                (
+                    p.clone(),
+                    Payload::Chosen {
+                        slot,
+                        value: value.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn heartbeat(&mut self) -> Vec<(String, Payload<S::Command>)> {
+        self.last_heartbeat = Instant::now();
+        self.peers
+            .iter()
+            .map(|p| {
+                (
+                    p.clone(),
+                    Payload::Heartbeat {
+                        ballot: self.ballot.clone(),
+                        chosen: self.next_apply,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn learn(&mut self, slot: usize, value: Value<S::Command>) {
+        if slot < self.next_apply {
+            return;
+        }
+        self.chosen.insert(slot, value);
+        // Accepted values stay around after being applied: a new leader that
+        // is behind learns the chosen ones from them in phase 1.
+        while let Some(value) = self.chosen.remove(&self.next_apply) {
+            self.decided.push(value.clone());
+            self.next_apply += 1;
+            if let Some(proposal) = value {
+                let output = self.state_machine.apply(&proposal.command);
+                self.applied.push((proposal, output));
+            }
+        }
+        self.next_slot = self.next_slot.max(self.next_apply);
+    }
+}
+
+impl<S: StateMachine> Consensus<S> for Paxos<S> {
+    type Payload = Payload<S::Command>;
+
+    fn propose(
+        &mut self,
+        proposal: Proposal<S::Command>,
+    ) -> Result<Vec<(String, Self::Payload)>, Option<String>> {
+        if !self.leading {
+            return Err(self.leader.clone());
+        }
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        Ok(self.accept(slot, Some(proposal)))
+    }
+
+    fn handle(&mut self, src: &str, payload: Self::Payload) -> Vec<(String, Self::Payload)> {
+        match payload {
+            Payload::Prepare { ballot, from_slot } => {
+                if ballot < self.promised {
+                    return vec![(
+                        src.to_string(),
+                        Payload::Nack {
+                            promised: self.promised.clone(),
+                        },
+                    )];
+                }
+                if ballot > self.ballot {
+                    self.step_down(&ballot);
+                }
+                self.promised = ballot.clone();
+                vec![(
+                    src.to_string(),
+                    Payload::Promise {
+                        ballot,
+                        accepted: self.accepted_from(from_slot),
+                    },
+                )]
+            }
+            Payload::Promise { ballot, accepted } => {
+                if ballot != self.ballot {
+                    return Vec::new();
+                }
+                if let Some(promises) = &mut self.promises {
+                    promises.insert(src.to_string(), accepted);
+                }
+                self.check_promises()
+            }
+            Payload::Accept {
+                ballot,
+                slot,
+                value,
+                rpc,
+            } => {
+                if ballot < self.promised {
+                    return vec![(
+                        src.to_string(),
+                        Payload::Nack {
+                            promised: self.promised.clone(),
+                        },
+                    )];
+                }
+                if ballot > self.ballot {
+                    self.step_down(&ballot);
+                }
+                self.promised = ballot.clone();
+                self.leader = Some(src.to_string());
+                self.reset_leader_deadline();
+                self.accepted.insert(slot, (ballot.clone(), value));
+                vec![(src.to_string(), Payload::Accepted { ballot, slot, rpc })]
+            }
+            Payload::Accepted { ballot, slot, rpc } => {
+                self.in_flight.ack(rpc);
+                if !self.leading || ballot != self.ballot {
+                    return Vec::new();
+                }
+                if let Some((_, acks)) = self.proposals.get_mut(&slot) {
+                    acks.insert(src.to_string());
+                }
+                self.check_accepted(slot)
+            }
+            Payload::Nack { promised } => {
+                if promised > self.ballot {
+                    self.step_down(&promised);
+                }
+                Vec::new()
+            }
+            Payload::Chosen { slot, value } => {
+                self.learn(slot, value);
+                Vec::new()
+            }
+            Payload::Heartbeat { ballot, chosen } => {
+                if ballot < self.promised {
+                    return Vec::new();
+                }
+                if ballot > self.ballot {
+                    self.step_down(&ballot);
+                }
+                self.leader = Some(src.to_string());
+                self.reset_leader_deadline();
+                if chosen > self.next_apply {
+                    return vec![(
+                        src.to_string(),
+                        Payload::Catchup {
+                            from_slot: self.next_apply,
+                        },
+                    )];
+                }
+                Vec::new()
+            }
+            Payload::Catchup { from_slot } => self.decided[from_slot.min(self.decided.len())..]
+                .iter()
+                .take(self.config.max_batch)
+                .enumerate()
+                .map(|(offset, value)| {
+                    (
+                        src.to_string(),
+                        Payload::Chosen {
+                            slot: from_slot + offset,
+                            value: value.clone(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn tick(&mut self) -> Vec<(String, Self::Payload)> {
+        if !self.leading {
+            if Instant::now() >= self.leader_deadline {
+                return self.prepare();
+            }
+            return Vec::new();
+        }
+        let mut out = Vec::new();
+        if self.last_heartbeat.elapsed() >= self.config.heartbeat {
+            out.extend(self.heartbeat());
+        }
+        for entry in self.in_flight.expired() {
+            let Some((value, acks)) = self.proposals.get(&entry.data) else {
+                continue;
+            };
+            if acks.contains(&entry.dest) {
+                continue;
+            }
+            let value = value.clone();
+            self.rpc += 1;
+            let payload = Payload::Accept {
+                ballot: self.ballot.clone(),
+                slot: entry.data,
+                value,
+                rpc: self.rpc,
+            };
+            out.push((entry.dest.clone(), payload));
+            self.in_flight.retry(self.rpc, entry);
+        }
+        out
+    }
+
+    fn take_applied(&mut self) -> Vec<(Proposal<S::Command>, S::Output)> {
+        std::mem::take(&mut self.applied)
+    }
+
+    fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+}
@@ -0,0 +1,422 @@
+//! Raft.
+//!
+//! Leader election with randomized timeouts, log replication through
+//! `append_entries`, and a commit index that only advances over entries from
+//! the leader's own term. State is kept in memory only; maelstrom's nemeses
+//! partition nodes but do not restart them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consensus::{Consensus, Proposal, StateMachine},
+    rng::Rng,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry<C> {
+    pub term: usize,
+    pub proposal: Proposal<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload<C> {
+    RequestVote {
+        term: usize,
+        last_log_index: usize,
+        last_log_term: usize,
+    },
+    RequestVoteOk {
+        term: usize,
+        granted: bool,
+    },
+    AppendEntries {
+        term: usize,
+        prev_log_index: usize,
+        prev_log_term: usize,
+        entries: Vec<Entry<C>>,
+        leader_commit: usize,
+    },
+    AppendEntriesOk {
+        term: usize,
+        success: bool,
+        /// On success the follower's last matching index, otherwise a hint
+        /// where the leader should back up to.
+        match_index: usize,
+    },
+}
+
+impl<C> Payload<C> {
+    fn term(&self) -> usize {
+        match self {
+            Payload::RequestVote { term, .. }
+            | Payload::RequestVoteOk { term, .. }
+            | Payload::AppendEntries { term, .. }
+            | Payload::AppendEntriesOk { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Election timeouts are drawn uniformly from this range.
+    pub election_timeout: (Duration, Duration),
+    pub heartbeat: Duration,
+    /// Most entries sent in one `append_entries`.
+    pub max_batch: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            election_timeout: (Duration::from_millis(300), Duration::from_millis(600)),
+            heartbeat: Duration::from_millis(100),
+            max_batch: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub struct Raft<S: StateMachine> {
+    me: String,
+    peers: Vec<String>,
+    config: Config,
+    rng: Rng,
+    role: Role,
+    term: usize,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    /// Entry `i` (1-based, as in the paper) lives at `log[i - 1]`.
+    log: Vec<Entry<S::Command>>,
+    commit_index: usize,
+    last_applied: usize,
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    votes: HashSet<String>,
+    next_index: HashMap<String, usize>,
+    match_index: HashMap<String, usize>,
+    state_machine: S,
+    applied: Vec<(Proposal<S::Command>, S::Output)>,
+}
+
+impl<S: StateMachine> Raft<S> {
+    pub fn new(me: &str, node_ids: &[String], state_machine: S, config: Config) -> Self {
+        let mut raft = Raft {
+            me: me.to_string(),
+            peers: node_ids.iter().filter(|n| *n != me).cloned().collect(),
+            config,
+            rng: Rng::for_node(me),
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            log: Vec::new(),
+            commit_index: 0,
+            last_applied: 0,
+            election_deadline: Instant::now(),
+            last_heartbeat: Instant::now(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            state_machine,
+            applied: Vec::new(),
+        };
+        raft.reset_election_deadline();
+        raft
+    }
+
+    pub fn term(&self) -> usize {
+        self.term
+    }
+
+    pub fn commit_index(&self) -> usize {
+        self.commit_index
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    fn majority(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn last_log_index(&self) -> usize {
+        self.log.len()
+    }
+
+    fn term_at(&self, index: usize) -> usize {
+        match index {
+            0 => 0,
+            i => self.log.get(i - 1).map_or(0, |e| e.term),
+        }
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let (min, max) = self.config.election_timeout;
+        let spread = max.saturating_sub(min).as_millis() as usize;
+        let jitter = Duration::from_millis(self.rng.below(spread.max(1)) as u64);
+        self.election_deadline = Instant::now() + min + jitter;
+    }
+
+    fn step_down(&mut self, term: usize) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        if self.role != Role::Follower {
+            self.role = Role::Follower;
+            self.reset_election_deadline();
+        }
+    }
+
+    fn start_election(&mut self) -> Vec<(String, Payload<S::Command>)> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.me.clone());
+        self.votes = HashSet::from([self.me.clone()]);
+        self.reset_election_deadline();
+        if self.votes.len() >= self.majority() {
+            return self.become_leader();
+        }
+        let payload = Payload::RequestVote {
+            term: self.term,
+            last_log_index: self.last_log_index(),
+            last_log_term: self.term_at(self.last_log_index()),
+        };
+        self.peers
+            .iter()
+            .map(|p| (p.clone(), payload.clone()))
+            .collect()
+    }
+
+    fn become_leader(&mut self) -> Vec<(String, Payload<S::Command>)> {
+        self.role = Role::Leader;
+        self.leader = Some(self.me.clone());
+        let next = self.last_log_index() + 1;
+        self.next_index = self.peers.iter().map(|p| (p.clone(), next)).collect();
+        self.match_index = self.peers.iter().map(|p| (p.clone(), 0)).collect();
+        self.broadcast_append()
+    }
+
+    fn append_for(&self, peer: &str) -> Payload<S::Command> {
+        let next = self.next_index.get(peer).copied().unwrap_or(1).max(1);
+        let prev_log_index = (next - 1).min(self.last_log_index());
+        Payload::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries: self.log[prev_log_index..]
+                .iter()
+                .take(self.config.max_batch)
+                .cloned()
+                .collect(),
+            leader_commit: self.commit_index,
+        }
+    }
+
+    fn broadcast_append(&mut self) -> Vec<(String, Payload<S::Command>)> {
+        self.last_heartbeat = Instant::now();
+        self.peers
+            .iter()
+            .map(|p| (p.clone(), self.append_for(p)))
+            .collect()
+    }
+
+    fn advance_commit(&mut self) {
+        for n in (self.commit_index + 1..=self.last_log_index()).rev() {
+            // Entries from earlier terms are only committed indirectly, see
+            // section 5.4.2 of the paper.
+            if self.term_at(n) != self.term {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|m| **m >= n).count();
+            if replicas >= self.majority() {
+                self.commit_index = n;
+                break;
+            }
+        }
+        self.apply();
+    }
+
+    fn apply(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let proposal = self.log[self.last_applied - 1].proposal.clone();
+            let output = self.state_machine.apply(&proposal.command);
+            self.applied.push((proposal, output));
+        }
+    }
+}
+
+impl<S: StateMachine> Consensus<S> for Raft<S> {
+    type Payload = Payload<S::Command>;
+
+    fn propose(
+        &mut self,
+        proposal: Proposal<S::Command>,
+    ) -> Result<Vec<(String, Self::Payload)>, Option<String>> {
+        if self.role != Role::Leader {
+            return Err(self.leader.clone());
+        }
+        self.log.push(Entry {
+            term: self.term,
+            proposal,
+        });
+        if self.peers.is_empty() {
+            self.advance_commit();
+        }
+        Ok(self.broadcast_append())
+    }
+
+    fn handle(&mut self, src: &str, payload: Self::Payload) -> Vec<(String, Self::Payload)> {
+        if payload.term() > self.term {
+            self.step_down(payload.term());
+            self.leader = None;
+        }
+        match payload {
+            Payload::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let my_last_term = self.term_at(self.last_log_index());
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (my_last_term, self.last_log_index());
+                let granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.as_deref().is_none_or(|v| v == src);
+                if granted {
+                    self.voted_for = Some(src.to_string());
+                    self.reset_election_deadline();
+                }
+                vec![(
+                    src.to_string(),
+                    Payload::RequestVoteOk {
+                        term: self.term,
+                        granted,
+                    },
+                )]
+            }
+            Payload::RequestVoteOk { term, granted } => {
+                if self.role != Role::Candidate || term != self.term || !granted {
+                    return Vec::new();
+                }
+                self.votes.insert(src.to_string());
+                if self.votes.len() >= self.majority() {
+                    return self.become_leader();
+                }
+                Vec::new()
+            }
+            Payload::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let reply = |term, success, match_index| {
+                    vec![(
+                        src.to_string(),
+                        Payload::AppendEntriesOk {
+                            term,
+                            success,
+                            match_index,
+                        },
+                    )]
+                };
+                if term < self.term {
+                    return reply(self.term, false, 0);
+                }
+                self.step_down(term);
+                self.leader = Some(src.to_string());
+                self.reset_election_deadline();
+                if prev_log_index > self.last_log_index()
+                    || self.term_at(prev_log_index) != prev_log_term
+                {
+                    let hint = self.last_log_index().min(prev_log_index.saturating_sub(1));
+                    return reply(self.term, false, hint);
+                }
+                let last_new = prev_log_index + entries.len();
+                for (offset, entry) in entries.into_iter().enumerate() {
+                    let index = prev_log_index + 1 + offset;
+                    if index <= self.last_log_index() {
+                        if self.term_at(index) == entry.term {
+                            continue;
+                        }
+                        self.log.truncate(index - 1);
+                    }
+                    self.log.push(entry);
+                }
+                if leader_commit > self.commit_index {
+                    self.commit_index = self.commit_index.max(leader_commit.min(last_new));
+                    self.apply();
+                }
+                reply(self.term, true, last_new)
+            }
+            Payload::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return Vec::new();
+                }
+                if success {
+                    let matched = self.match_index.entry(src.to_string()).or_default();
+                    *matched = (*matched).max(match_index);
+                    let next = *matched + 1;
+                    self.next_index.insert(src.to_string(), next);
+                    self.advance_commit();
+                    if next <= self.last_log_index() {
+                        return vec![(src.to_string(), self.append_for(src))];
+                    }
+                    Vec::new()
+                } else {
+                    let next = self.next_index.entry(src.to_string()).or_insert(1);
+                    *next = (match_index + 1).min(next.saturating_sub(1)).max(1);
+                    vec![(src.to_string(), self.append_for(src))]
+                }
+            }
+        }
+    }
+
+    fn tick(&mut self) -> Vec<(String, Self::Payload)> {
+        match self.role {
+            Role::Leader if self.last_heartbeat.elapsed() >= self.config.heartbeat => {
+                self.broadcast_append()
+            }
+            Role::Leader => Vec::new(),
+            Role::Follower | Role::Candidate if Instant::now() >= self.election_deadline => {
+                self.start_election()
+            }
+            Role::Follower | Role::Candidate => Vec::new(),
+        }
+    }
+
+    fn take_applied(&mut self) -> Vec<(Proposal<S::Command>, S::Output)> {
+        std::mem::take(&mut self.applied)
+    }
+
+    fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    fn state_machine(&self) -> &S {
+        &self.state_machine
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Messages sent to a peer that have not been acknowledged yet, keyed by the
+/// `msg_id` they went out with.
+///
+/// Entries time out after `timeout`; [`InFlight::expired`] hands them back to
+/// be resent under a fresh id until `max_attempts` is used up. At most
+/// `capacity` entries are kept, the oldest one is dropped to make room.
+#[derive(Debug)]
+pub struct InFlight<T> {
+    entries: HashMap<usize, Entry<T>>,
+    timeout: Duration,
+    max_attempts: usize,
+    capacity: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry<T> {
+    pub dest: String,
+    pub data: T,
+    pub sent_at: Instant,
+    pub attempts: usize,
+}
+
+impl<T> InFlight<T> {
+    pub fn new(timeout: Duration, max_attempts: usize, capacity: usize) -> Self {
+        InFlight {
+            entries: HashMap::new(),
+            timeout,
+            max_attempts,
+            capacity,
+        }
+    }
+
+    pub fn track(&mut self, msg_id: usize, dest: String, data: T) {
+        self.insert(
+            msg_id,
+            Entry {
+                dest,
+                data,
+                sent_at: Instant::now(),
+                attempts: 1,
+            },
+        );
+    }
+
+    /// Track a resend of an entry returned by [`InFlight::expired`].
+    pub fn retry(&mut self, msg_id: usize, entry: Entry<T>) {
+        self.insert(
+            msg_id,
+            Entry {
+                sent_at: Instant::now(),
+                attempts: entry.attempts + 1,
+                ..entry
+            },
+        );
+    }
+
+    fn insert(&mut self, msg_id: usize, entry: Entry<T>) {
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.sent_at)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(msg_id, entry);
+    }
+
+    pub fn ack(&mut self, msg_id: usize) -> Option<Entry<T>> {
+        self.entries.remove(&msg_id)
+    }
+
+    /// Remove timed out entries. The ones with attempts left are returned;
+    /// the rest are dropped.
+    pub fn expired(&mut self) -> Vec<Entry<T>> {
+        let now = Instant::now();
+        let ids: Vec<usize> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| now.duration_since(e.sent_at) >= self.timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.entries.remove(&id))
+            .filter(|e| e.attempts < self.max_attempts)
+            .collect()
+    }
+
+    /// Drop entries that no longer need an ack.
+    pub fn retain(&mut self, mut f: impl FnMut(&Entry<T>) -> bool) {
+        self.entries.retain(|_, e| f(e));
+    }
+
+    pub fn to<'a>(&'a self, dest: &'a str) -> impl Iterator<Item = &'a T> + 'a {
+        self.entries
+            .values()
+            .filter(move |e| e.dest == dest)
+            .map(|e| &e.data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
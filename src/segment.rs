@@ -0,0 +1,181 @@
+//! Segmented append-only logs.
+//!
+//! A log is split into segments of `segment_size` consecutive offsets, so
+//! old data can be dropped a whole segment at a time: by age, by total size,
+//! or by compaction below an offset every consumer is past. Offsets may
+//! arrive out of order (replication does not preserve it); an entry lands in
+//! whichever segment covers its offset.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+pub const SEGMENT_SIZE_ENV: &str = "RUSTENGAN_SEGMENT_SIZE";
+pub const RETENTION_MS_ENV: &str = "RUSTENGAN_RETENTION_MS";
+pub const RETENTION_ENTRIES_ENV: &str = "RUSTENGAN_RETENTION_ENTRIES";
+pub const COMPACT_ENV: &str = "RUSTENGAN_COMPACT";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub segment_size: usize,
+    /// Drop segments not written to for this long.
+    pub retention_age: Option<Duration>,
+    /// Drop the oldest segments while the log holds more entries than this.
+    pub retention_entries: Option<usize>,
+    /// Drop segments that lie entirely below the committed offset.
+    pub compact: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            segment_size: 1024,
+            retention_age: None,
+            retention_entries: None,
+            compact: false,
+        }
+    }
+}
+
+impl Config {
+    /// Defaults keep everything forever. `RUSTENGAN_SEGMENT_SIZE`,
+    /// `RUSTENGAN_RETENTION_MS`, `RUSTENGAN_RETENTION_ENTRIES` and
+    /// `RUSTENGAN_COMPACT=1` override them.
+    pub fn from_env() -> anyhow::Result<Self> {
+        fn var(name: &str) -> anyhow::Result<Option<usize>> {
+            match std::env::var(name) {
+                Ok(s) => s
+                    .parse()
+                    .map(Some)
+                    .with_context(|| format!("invalid {name}: {s}")),
+                Err(_) => Ok(None),
+            }
+        }
+        let default = Config::default();
+        Ok(Config {
+            segment_size: var(SEGMENT_SIZE_ENV)?
+                .unwrap_or(default.segment_size)
+                .max(1),
+            retention_age: var(RETENTION_MS_ENV)?.map(|ms| Duration::from_millis(ms as u64)),
+            retention_entries: var(RETENTION_ENTRIES_ENV)?,
+            compact: var(COMPACT_ENV)?.is_some_and(|c| c != 0),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Segment<T> {
+    entries: BTreeMap<usize, T>,
+    last_write: Instant,
+}
+
+#[derive(Debug)]
+pub struct SegmentedLog<T> {
+    segment_size: usize,
+    /// Segments by their first offset.
+    segments: BTreeMap<usize, Segment<T>>,
+    /// Offsets below this were dropped.
+    start: usize,
+    len: usize,
+}
+
+impl<T> SegmentedLog<T> {
+    pub fn new(segment_size: usize) -> Self {
+        SegmentedLog {
+            segment_size: segment_size.max(1),
+            segments: BTreeMap::new(),
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Earliest offset still available; anything below it was dropped by
+    /// retention or compaction.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Store `value` at `offset`. Offsets below [`SegmentedLog::start`] are
+    /// ignored, they belong to data that was already dropped.
+    pub fn insert(&mut self, offset: usize, value: T) {
+        if offset < self.start {
+            return;
+        }
+        let base = offset - offset % self.segment_size;
+        let segment = self.segments.entry(base).or_insert_with(|| Segment {
+            entries: BTreeMap::new(),
+            last_write: Instant::now(),
+        });
+        segment.last_write = Instant::now();
+        if segment.entries.insert(offset, value).is_none() {
+            self.len += 1;
+        }
+    }
+
+    pub fn get(&self, offset: usize) -> Option<&T> {
+        let base = offset - offset % self.segment_size;
+        self.segments.get(&base)?.entries.get(&offset)
+    }
+
+    /// Entries from `offset` on, in offset order.
+    pub fn range_from(&self, offset: usize) -> impl Iterator<Item = (&usize, &T)> {
+        let base = offset - offset % self.segment_size;
+        self.segments
+            .range(base..)
+            .flat_map(move |(_, segment)| segment.entries.range(offset..))
+    }
+
+    /// Drop segments past the retention limits. The newest segment is
+    /// always kept, it is the one still being written.
+    pub fn retain(&mut self, config: &Config) {
+        let now = Instant::now();
+        while self.segments.len() > 1 {
+            let Some((_, oldest)) = self.segments.first_key_value() else {
+                break;
+            };
+            let expired = config
+                .retention_age
+                .is_some_and(|age| now.duration_since(oldest.last_write) > age);
+            let oversized = config.retention_entries.is_some_and(|max| self.len > max);
+            if !expired && !oversized {
+                break;
+            }
+            self.drop_oldest();
+        }
+    }
+
+    /// Drop segments that lie entirely below `offset`.
+    pub fn compact(&mut self, offset: usize) {
+        while let Some((base, _)) = self.segments.first_key_value() {
+            if base + self.segment_size > offset {
+                break;
+            }
+            self.drop_oldest();
+        }
+    }
+
+    /// Drop everything below `offset` and never take it back, as when
+    /// restoring a log that retention or compaction cut there before.
+    pub fn truncate(&mut self, offset: usize) {
+        self.compact(offset);
+        self.start = self.start.max(offset);
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some((base, segment)) = self.segments.pop_first() {
+            self.len -= segment.entries.len();
+            self.start = self.start.max(base + self.segment_size);
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Snapshot state transfer.
+//!
+//! A node too far behind to catch up through incremental replication (a new
+//! node, or one restarted without its state) is sent a snapshot instead: the
+//! sender serializes its whole state once, cuts the JSON into chunks and
+//! streams them with a bounded window, resending chunks that go unacked. The
+//! receiver reassembles them and hands the state back together with the
+//! version it was taken at, so the node can install it and resume
+//! incremental replication from there.
+//!
+//! Like the other protocols this is sans-IO: calls return `(dest, Payload)`
+//! pairs for the node to wrap into its own payload type.
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::rng::Rng;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload<V> {
+    Chunk {
+        transfer: u64,
+        index: usize,
+        total: usize,
+        /// Only on the first chunk.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<V>,
+        data: String,
+    },
+    ChunkOk {
+        transfer: u64,
+        index: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Bytes of serialized state per chunk.
+    pub chunk_size: usize,
+    /// Chunks sent but not yet acknowledged, per transfer.
+    pub window: usize,
+    /// Resend a chunk that has not been acknowledged after this long.
+    pub timeout: Duration,
+    /// Give up on a transfer after this many timeouts without progress.
+    pub attempts: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            chunk_size: 16 * 1024,
+            window: 8,
+            timeout: Duration::from_millis(500),
+            attempts: 10,
+        }
+    }
+}
+
+/// A snapshot that arrived in full.
+#[derive(Debug)]
+pub struct Installed<V, S> {
+    pub from: String,
+    pub version: V,
+    pub state: S,
+}
+
+#[derive(Debug)]
+struct Outgoing<V> {
+    transfer: u64,
+    version: V,
+    chunks: Vec<String>,
+    acked: Vec<bool>,
+    sent_at: Vec<Option<Instant>>,
+    last_progress: Instant,
+}
+
+impl<V: Clone> Outgoing<V> {
+    fn chunk(&self, index: usize) -> Payload<V> {
+        Payload::Chunk {
+            transfer: self.transfer,
+            index,
+            total: self.chunks.len(),
+            version: (index == 0).then(|| self.version.clone()),
+            data: self.chunks[index].clone(),
+        }
+    }
+
+    /// Send chunks that were never sent, or timed out, while the window
+    /// allows.
+    fn fill(&mut self, window: usize, timeout: Duration) -> Vec<Payload<V>> {
+        let now = Instant::now();
+        let mut in_flight = (0..self.chunks.len())
+            .filter(|&i| {
+                !self.acked[i] && self.sent_at[i].is_some_and(|t| now.duration_since(t) < timeout)
+            })
+            .count();
+        let mut out = Vec::new();
+        for i in 0..self.chunks.len() {
+            if in_flight >= window {
+                break;
+            }
+            let due = self.sent_at[i].is_none_or(|t| now.duration_since(t) >= timeout);
+            if !self.acked[i] && due {
+                self.sent_at[i] = Some(now);
+                in_flight += 1;
+                out.push(self.chunk(i));
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug)]
+struct Incoming<V> {
+    version: Option<V>,
+    chunks: Vec<Option<String>>,
+    last_seen: Instant,
+}
+
+/// Snapshots on their way to and from peers, at most one per direction and
+/// peer. `V` is the version a snapshot was taken at and `S` the state.
+#[derive(Debug)]
+pub struct Transfers<V, S> {
+    outgoing: HashMap<String, Outgoing<V>>,
+    incoming: HashMap<(String, u64), Incoming<V>>,
+    /// Finished transfers and when they finished, so late chunks are
+    /// acknowledged but not installed twice. Forgotten once late chunks
+    /// would have given up too.
+    done: HashMap<(String, u64), Instant>,
+    installed: Vec<Installed<V, S>>,
+    rng: Rng,
+    config: Config,
+    _state: PhantomData<S>,
+}
+
+impl<V, S> Transfers<V, S>
+where
+    V: Clone + Serialize + DeserializeOwned,
+    S: Serialize + DeserializeOwned,
+{
+    pub fn new(me: &str, config: Config) -> Self {
+        Transfers {
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            done: HashMap::new(),
+            installed: Vec::new(),
+            // Transfer ids are random so a restarted sender does not reuse
+            // one the peer already finished.
+            rng: Rng::for_node(me),
+            config,
+            _state: PhantomData,
+        }
+    }
+
+    /// The version of the snapshot currently streaming to `peer`, if any.
+    pub fn sending(&self, peer: &str) -> Option<&V> {
+        self.outgoing.get(peer).map(|o| &o.version)
+    }
+
+    /// Start streaming `state`, taken at `version`, to `peer`. Does nothing
+    /// while an earlier snapshot to `peer` is still on its way.
+    pub fn send(
+        &mut self,
+        peer: &str,
+        version: V,
+        state: &S,
+    ) -> anyhow::Result<Vec<(String, Payload<V>)>> {
+        if self.outgoing.contains_key(peer) {
+            return Ok(Vec::new());
+        }
+        let json = serde_json::to_string(state).context("serialize snapshot")?;
+        let chunks = split(&json, self.config.chunk_size);
+        let mut outgoing = Outgoing {
+            // Kept to 53 bits so the id survives any JSON parser.
+            transfer: self.rng.next_u64() >> 11,
+            version,
+            acked: vec![false; chunks.len()],
+            sent_at: vec![None; chunks.len()],
+            chunks,
+            last_progress: Instant::now(),
+        };
+        let out = outgoing.fill(self.config.window, self.config.timeout);
+        self.outgoing.insert(peer.to_string(), outgoing);
+        Ok(out.into_iter().map(|p| (peer.to_string(), p)).collect())
+    }
+
+    pub fn handle(&mut self, src: &str, payload: &Payload<V>) -> Vec<(String, Payload<V>)> {
+        match payload {
+            Payload::Chunk {
+                transfer,
+                index,
+                total,
+                version,
+                data,
+            } => {
+                let ack = vec![(
+                    src.to_string(),
+                    Payload::ChunkOk {
+                        transfer: *transfer,
+                        index: *index,
+                    },
+                )];
+                let key = (src.to_string(), *transfer);
+                if self.done.contains_key(&key) || index >= total {
+                    return ack;
+                }
+                // A newer snapshot from the same peer supersedes older ones.
+                self.incoming
+                    .retain(|(from, t), _| from != src || t == transfer);
+                let incoming = self
+                    .incoming
+                    .entry(key.clone())
+                    .or_insert_with(|| Incoming {
+                        version: None,
+                        chunks: vec![None; *total],
+                        last_seen: Instant::now(),
+                    });
+                if incoming.chunks.len() != *total {
+                    return ack;
+                }
+                incoming.last_seen = Instant::now();
+                incoming.chunks[*index] = Some(data.clone());
+                if version.is_some() {
+                    incoming.version = version.clone();
+                }
+                if incoming.version.is_some() && incoming.chunks.iter().all(Option::is_some) {
+                    let incoming = self.incoming.remove(&key).expect("just looked up");
+                    self.done.insert(key, Instant::now());
+                    let json: String = incoming.chunks.into_iter().flatten().collect();
+                    // A snapshot that does not parse is dropped; the sender
+                    // will offer another one while we are still behind.
+                    if let (Some(version), Ok(state)) =
+                        (incoming.version, serde_json::from_str(&json))
+                    {
+                        self.installed.push(Installed {
+                            from: src.to_string(),
+                            version,
+                            state,
+                        });
+                    }
+                }
+                ack
+            }
+            Payload::ChunkOk { transfer, index } => {
+                let Some(outgoing) = self.outgoing.get_mut(src) else {
+                    return Vec::new();
+                };
+                if outgoing.transfer != *transfer || *index >= outgoing.chunks.len() {
+                    return Vec::new();
+                }
+                if !outgoing.acked[*index] {
+                    outgoing.acked[*index] = true;
+                    outgoing.last_progress = Instant::now();
+                }
+                if outgoing.acked.iter().all(|a| *a) {
+                    self.outgoing.remove(src);
+                    return Vec::new();
+                }
+                let out = outgoing.fill(self.config.window, self.config.timeout);
+                out.into_iter().map(|p| (src.to_string(), p)).collect()
+            }
+        }
+    }
+
+    /// Resend timed out chunks, and drop transfers that stopped making
+    /// progress in either direction.
+    pub fn tick(&mut self) -> Vec<(String, Payload<V>)> {
+        let give_up = self.config.timeout * self.config.attempts as u32;
+        self.outgoing
+            .retain(|_, o| o.last_progress.elapsed() < give_up);
+        self.incoming.retain(|_, i| i.last_seen.elapsed() < give_up);
+        self.done.retain(|_, at| at.elapsed() < give_up);
+        let mut out = Vec::new();
+        for (peer, outgoing) in &mut self.outgoing {
+            for payload in outgoing.fill(self.config.window, self.config.timeout) {
+                out.push((peer.clone(), payload));
+            }
+        }
+        out
+    }
+
+    /// Snapshots received in full since the last call, ready to install.
+    pub fn take_installed(&mut self) -> Vec<Installed<V, S>> {
+        std::mem::take(&mut self.installed)
+    }
+}
+
+/// Cut `s` into pieces of at most `size` bytes (at least one character),
+/// never splitting a character.
+fn split(s: &str, size: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk.to_string());
+        rest = tail;
+    }
+    if chunks.is_empty() {
+        chunks.push(String::new());
+    }
+    chunks
+}
@@ -0,0 +1,283 @@
+//! Durable node state.
+//!
+//! Nodes persist their state as a stream of records (an entry appended, a
+//! value written, ...) and rebuild it on startup by replaying them. Records
+//! go either nowhere ([`NoStorage`], the default), to a list in memory
+//! ([`Memory`]), or to an append-only write-ahead log file ([`Wal`]) when
+//! `RUSTENGAN_DATA_DIR` is set.
+//!
+//! The WAL holds one JSON record per line. How often it reaches the disk is
+//! up to the [`SyncPolicy`]: records written but not yet synced sit in a
+//! userspace buffer, so a process that is killed loses them, while a clean
+//! shutdown flushes them.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+pub const DATA_DIR_ENV: &str = "RUSTENGAN_DATA_DIR";
+pub const FSYNC_ENV: &str = "RUSTENGAN_FSYNC";
+
+pub trait Storage<R> {
+    /// Persist `record`, as durably as the storage promises.
+    fn append(&mut self, record: &R) -> anyhow::Result<()>;
+
+    /// Every record that survived, oldest first.
+    fn replay(&mut self) -> anyhow::Result<Vec<R>>;
+
+    /// Force everything appended so far to stable storage.
+    fn sync(&mut self) -> anyhow::Result<()>;
+
+    /// Replace everything persisted so far with `records`: a checkpoint of
+    /// the current state, once older records describe data the node dropped.
+    fn rewrite(&mut self, records: &[R]) -> anyhow::Result<()>;
+
+    /// Periodic work, e.g. syncing on an interval.
+    fn tick(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps nothing: the node's state lives in its own memory anyway, and
+/// replay only happens at startup, when a process has nothing to replay.
+#[derive(Debug)]
+pub struct NoStorage<R> {
+    _record: PhantomData<R>,
+}
+
+impl<R> Default for NoStorage<R> {
+    fn default() -> Self {
+        NoStorage {
+            _record: PhantomData,
+        }
+    }
+}
+
+impl<R> Storage<R> for NoStorage<R> {
+    fn append(&mut self, _record: &R) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn replay(&mut self) -> anyhow::Result<Vec<R>> {
+        Ok(Vec::new())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn rewrite(&mut self, _records: &[R]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps records for the lifetime of the value only, e.g. to rebuild a
+/// node within one process.
+#[derive(Debug)]
+pub struct Memory<R> {
+    records: Vec<R>,
+}
+
+impl<R> Default for Memory<R> {
+    fn default() -> Self {
+        Memory {
+            records: Vec::new(),
+        }
+    }
+}
+
+impl<R: Clone> Storage<R> for Memory<R> {
+    fn append(&mut self, record: &R) -> anyhow::Result<()> {
+        self.records.push(record.clone());
+        Ok(())
+    }
+
+    fn replay(&mut self) -> anyhow::Result<Vec<R>> {
+        Ok(self.records.clone())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn rewrite(&mut self, records: &[R]) -> anyhow::Result<()> {
+        self.records = records.to_vec();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync after every record.
+    Always,
+    /// fsync from [`Storage::tick`] once this much time has passed.
+    Interval(Duration),
+    /// Leave it to the buffer filling up and the OS.
+    Never,
+}
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = anyhow::Error;
+
+    /// `always`, `never` or `interval:<ms>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "always" => Ok(SyncPolicy::Always),
+            None if s == "never" => Ok(SyncPolicy::Never),
+            Some(("interval", ms)) => Ok(SyncPolicy::Interval(Duration::from_millis(
+                ms.parse().context("invalid fsync interval")?,
+            ))),
+            _ => Err(anyhow::anyhow!("unknown fsync policy: {s}")),
+        }
+    }
+}
+
+pub struct Wal<R> {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    policy: SyncPolicy,
+    last_sync: Instant,
+    dirty: bool,
+    _record: PhantomData<R>,
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(path)
+        .with_context(|| format!("open wal {}", path.display()))
+}
+
+impl<R> Wal<R> {
+    pub fn open(path: impl AsRef<Path>, policy: SyncPolicy) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("create data dir {}", dir.display()))?;
+        }
+        let file = open_append(&path)?;
+        Ok(Wal {
+            path,
+            writer: BufWriter::new(file),
+            policy,
+            last_sync: Instant::now(),
+            dirty: false,
+            _record: PhantomData,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<R: Serialize + DeserializeOwned> Storage<R> for Wal<R> {
+    fn append(&mut self, record: &R) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.writer, record).context("serialize wal record")?;
+        self.writer.write_all(b"\n").context("write wal record")?;
+        self.dirty = true;
+        if self.policy == SyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// A record cut short by a crash mid-write ends the replay, and the file
+    /// is truncated to the last complete record so new ones follow on cleanly.
+    fn replay(&mut self) -> anyhow::Result<Vec<R>> {
+        self.writer.flush().context("flush wal")?;
+        let mut file = File::open(&self.path).context("reopen wal for replay")?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        let mut valid = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).context("read wal")?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            match serde_json::from_str(&line) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+            valid += read as u64;
+        }
+        let file = self.writer.get_ref();
+        if file.metadata()?.len() > valid {
+            file.set_len(valid).context("truncate torn wal record")?;
+        }
+        Ok(records)
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().context("flush wal")?;
+        self.writer.get_ref().sync_data().context("fsync wal")?;
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Written to a temporary file that is synced and then renamed over the
+    /// WAL, so a crash leaves either the old log or the new one.
+    fn rewrite(&mut self, records: &[R]) -> anyhow::Result<()> {
+        self.writer.flush().context("flush wal")?;
+        let tmp = self.path.with_extension("wal.tmp");
+        let mut writer = BufWriter::new(
+            File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?,
+        );
+        for record in records {
+            serde_json::to_writer(&mut writer, record).context("serialize wal record")?;
+            writer.write_all(b"\n").context("write wal record")?;
+        }
+        writer.flush().context("flush rewritten wal")?;
+        writer.get_ref().sync_all().context("fsync rewritten wal")?;
+        std::fs::rename(&tmp, &self.path).context("replace wal")?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .context("fsync data dir")?;
+        }
+        self.writer = BufWriter::new(open_append(&self.path)?);
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> anyhow::Result<()> {
+        match self.policy {
+            SyncPolicy::Interval(every) if self.dirty && self.last_sync.elapsed() >= every => {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Storage for `node`'s `name` records: a WAL at
+/// `$RUSTENGAN_DATA_DIR/<node>/<name>.wal` synced per `RUSTENGAN_FSYNC`
+/// (default `always`), or nothing when no data dir is set.
+pub fn open<R>(node: &str, name: &str) -> anyhow::Result<Box<dyn Storage<R>>>
+where
+    R: Serialize + DeserializeOwned + 'static,
+{
+    let Ok(dir) = std::env::var(DATA_DIR_ENV) else {
+        return Ok(Box::new(NoStorage::default()));
+    };
+    let policy = match std::env::var(FSYNC_ENV) {
+        Ok(s) => s.parse()?,
+        Err(_) => SyncPolicy::Always,
+    };
+    let path = Path::new(&dir).join(node).join(format!("{name}.wal"));
+    Ok(Box::new(Wal::open(path, policy)?))
+}
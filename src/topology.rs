@@ -0,0 +1,203 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    str::FromStr,
+};
+
+use crate::{cmp_node_ids, rng::Rng};
+
+pub const TOPOLOGY_ENV: &str = "RUSTENGAN_TOPOLOGY";
+pub const DEFAULT_FANOUT: usize = 4;
+
+/// How a node picks the peers it gossips with.
+///
+/// Parsed from `RUSTENGAN_TOPOLOGY` at init, e.g. `given`, `spanning-tree`,
+/// `tree:4`, `star:2` or `expander:3`. Every node computes the same graph from
+/// the sorted node list, so the edges are symmetric without any coordination.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Use whatever maelstrom sends in the `topology` message.
+    #[default]
+    Given,
+    /// BFS tree over the maelstrom topology, rooted at the first node.
+    SpanningTree,
+    /// k-ary tree over the sorted node list.
+    KAryTree(usize),
+    /// The first `hubs` nodes form a clique, every other node hangs off one hub.
+    Star { hubs: usize },
+    /// Union of random rings, `degree` is rounded up to an even number.
+    Expander { degree: usize },
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg.parse::<usize>()?)),
+            None => (s, None),
+        };
+        match name {
+            "given" | "grid" => Ok(Strategy::Given),
+            "spanning-tree" => Ok(Strategy::SpanningTree),
+            "tree" => Ok(Strategy::KAryTree(arg.unwrap_or(DEFAULT_FANOUT).max(1))),
+            "star" => Ok(Strategy::Star {
+                hubs: arg.unwrap_or(1).max(1),
+            }),
+            "expander" => Ok(Strategy::Expander {
+                degree: arg.unwrap_or(4).max(2),
+            }),
+            _ => Err(anyhow::anyhow!("unknown topology strategy {s}")),
+        }
+    }
+}
+
+impl Strategy {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(TOPOLOGY_ENV) {
+            Ok(s) => s.parse(),
+            Err(_) => Ok(Strategy::default()),
+        }
+    }
+
+    /// Strategies that need the maelstrom topology before they can be computed.
+    pub fn needs_given(&self) -> bool {
+        matches!(self, Strategy::Given | Strategy::SpanningTree)
+    }
+
+    /// Neighbours of `node`. `given` is the maelstrom topology, if one has
+    /// arrived yet. Strategies that depend on it fall back to a k-ary tree when
+    /// it is missing; `given` also does when it does not mention `node`. The
+    /// spanning tree always covers all of `node_ids`, so every node agrees.
+    pub fn neighbours(
+        &self,
+        node: &str,
+        node_ids: &[String],
+        given: Option<&HashMap<String, Vec<String>>>,
+    ) -> Vec<String> {
+        let nodes = sorted(node_ids);
+        let Some(me) = nodes.iter().position(|n| n == node) else {
+            return Vec::new();
+        };
+        let edges = match (self, given) {
+            (Strategy::Given, Some(given)) if given.contains_key(node) => {
+                given[node].iter().cloned().collect()
+            }
+            (Strategy::SpanningTree, Some(given)) => spanning_tree(&nodes, given, me),
+            (Strategy::Given | Strategy::SpanningTree, _) => k_ary_tree(&nodes, DEFAULT_FANOUT, me),
+            (Strategy::KAryTree(k), _) => k_ary_tree(&nodes, *k, me),
+            (Strategy::Star { hubs }, _) => star(&nodes, *hubs, me),
+            (Strategy::Expander { degree }, _) => expander(&nodes, *degree, me),
+        };
+        let mut edges: Vec<String> = edges.into_iter().filter(|n| n != node).collect();
+        edges.sort_by(|a, b| cmp_node_ids(a, b));
+        edges
+    }
+}
+
+/// Order nodes numerically so every node agrees on positions.
+fn sorted(node_ids: &[String]) -> Vec<String> {
+    let mut nodes = node_ids.to_vec();
+    nodes.sort_by(|a, b| cmp_node_ids(a, b));
+    nodes.dedup();
+    nodes
+}
+
+fn k_ary_tree(nodes: &[String], k: usize, me: usize) -> BTreeSet<String> {
+    let mut edges = BTreeSet::new();
+    if me > 0 {
+        edges.insert(nodes[(me - 1) / k].clone());
+    }
+    for child in (k * me + 1)..=(k * me + k) {
+        if let Some(n) = nodes.get(child) {
+            edges.insert(n.clone());
+        }
+    }
+    edges
+}
+
+fn star(nodes: &[String], hubs: usize, me: usize) -> BTreeSet<String> {
+    let hubs = hubs.min(nodes.len());
+    if me < hubs {
+        let mut edges: BTreeSet<String> = nodes[..hubs].iter().cloned().collect();
+        edges.extend(
+            nodes
+                .iter()
+                .enumerate()
+                .skip(hubs)
+                .filter(|(i, _)| i % hubs == me)
+                .map(|(_, n)| n.clone()),
+        );
+        edges
+    } else {
+        BTreeSet::from([nodes[me % hubs].clone()])
+    }
+}
+
+fn expander(nodes: &[String], degree: usize, me: usize) -> BTreeSet<String> {
+    let mut edges = BTreeSet::new();
+    if nodes.len() < 2 {
+        return edges;
+    }
+    // Seeded by the node list so every node builds the same rings.
+    let mut rng = Rng::from_hash(nodes);
+    for _ in 0..degree.div_ceil(2) {
+        let mut ring: Vec<usize> = (0..nodes.len()).collect();
+        rng.shuffle(&mut ring);
+        let pos = ring.iter().position(|&i| i == me).expect("node is in ring");
+        let len = ring.len();
+        edges.insert(nodes[ring[(pos + 1) % len]].clone());
+        edges.insert(nodes[ring[(pos + len - 1) % len]].clone());
+    }
+    edges
+}
+
+fn spanning_tree(
+    nodes: &[String],
+    given: &HashMap<String, Vec<String>>,
+    me: usize,
+) -> BTreeSet<String> {
+    // Treat the given topology as undirected, keeping only cluster members.
+    let members: HashSet<&str> = nodes.iter().map(String::as_str).collect();
+    let mut adjacency: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+    for (n, ns) in given {
+        if !members.contains(n.as_str()) {
+            continue;
+        }
+        for m in ns.iter().filter(|m| members.contains(m.as_str())) {
+            adjacency.entry(n).or_default().insert(m);
+            adjacency.entry(m).or_default().insert(n);
+        }
+    }
+    let root = nodes[0].as_str();
+    let mut parent: HashMap<&str, &str> = HashMap::new();
+    let mut seen: HashSet<&str> = HashSet::from([root]);
+    let mut queue = VecDeque::from([root]);
+    while let Some(n) = queue.pop_front() {
+        let mut next: Vec<&str> = adjacency
+            .get(n)
+            .map(|ns| ns.iter().copied().collect())
+            .unwrap_or_default();
+        next.sort_by(|a, b| cmp_node_ids(a, b));
+        for m in next {
+            if seen.insert(m) {
+                parent.insert(m, n);
+                queue.push_back(m);
+            }
+        }
+    }
+    // Anything the given topology does not connect hangs off the root.
+    for n in nodes {
+        if !seen.contains(n.as_str()) {
+            parent.insert(n, root);
+        }
+    }
+    let me = nodes[me].as_str();
+    let mut edges: BTreeSet<String> = parent
+        .iter()
+        .filter(|(_, p)| **p == me)
+        .map(|(c, _)| c.to_string())
+        .collect();
+    if let Some(p) = parent.get(me) {
+        edges.insert(p.to_string());
+    }
+    edges
+}